@@ -1,12 +1,23 @@
+#[cfg(feature = "ffi")]
 use std::env;
+#[cfg(feature = "ffi")]
 use std::env::var;
 
-use std::path::PathBuf;
+#[cfg(feature = "ffi")]
+use std::path::{Path, PathBuf};
 
-const HEADER_FILE_NAME: &'static str = "BME680_driver/bme680.h";
+#[cfg(feature = "ffi")]
+const HEADER_FILE_NAME: &str = "BME680_driver/bme680.h";
 
+#[cfg(feature = "ffi")]
 fn main() {
     let project_dir = var("CARGO_MANIFEST_DIR").unwrap();
+    if !Path::new(&project_dir).join(HEADER_FILE_NAME).exists() {
+        panic!(
+            "the ffi feature needs Bosch's driver in BME680_driver/, \
+             run `git submodule update --init`"
+        );
+    }
 
     println!("cargo:rustc-link-search={}/BME680_driver/", project_dir);
     println!("cargo:rustc-link-lib=bme680");
@@ -25,6 +36,9 @@ fn main() {
     cc::Build::new()
         .file("BME680_driver/bme680.c")
         .pic(true)
-        .shared_flag(true)
         .compile("bme680")
 }
+
+// the native driver doesn't need any code generation
+#[cfg(not(feature = "ffi"))]
+fn main() {}
@@ -0,0 +1,466 @@
+//!
+//! Async sensor API on top of `embedded-hal-async`, e.g. for tokio or embassy based services.
+//!
+//! Waiting for a measurement (including the heater) yields to the executor instead of
+//! blocking the thread, so many sensors can be read concurrently. Settings, measurements
+//! and the native driver are shared with `BME680`, only the bus and the delay are async.
+//!
+
+use crate::config::{Bme680Config, ConfigRegisters};
+use crate::controller::{self, Access, Controller};
+use crate::errors::{BusError, BusOperation, SensorError};
+use crate::iaq::Iaq;
+use crate::interface::{AsyncInterface, HardwareChipSelect, I2cInterface, SpiInterface};
+use crate::native::{self, AsyncBus};
+use crate::source::*;
+use crate::{Bme680Address, Bme680Data, GasScan, Transport};
+
+use embedded_hal_async::delay::DelayNs;
+
+#[cfg(feature = "std")]
+use crate::sampling::Cadence;
+#[cfg(feature = "std")]
+use crate::Timestamped;
+#[cfg(feature = "std")]
+use futures_util::stream::{self, Stream};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+///
+/// `Transport` of an async bus
+///
+struct AsyncTransport<I, D>(Transport<I, D>);
+
+impl<I: AsyncInterface, D: DelayNs> AsyncBus for AsyncTransport<I, D> {
+    async fn read(&mut self, reg_addr: u8, data: &mut [u8]) -> i8 {
+        let result = self.0.interface.read(reg_addr, data).await;
+        self.0.result(BusOperation::Read, reg_addr, result)
+    }
+
+    async fn write(&mut self, reg_addr: u8, data: &[u8]) -> i8 {
+        let result = self.0.interface.write(reg_addr, data).await;
+        self.0.result(BusOperation::Write, reg_addr, result)
+    }
+
+    async fn delay_ms(&mut self, period: u32) {
+        self.0.delay.delay_ms(period).await;
+    }
+}
+
+impl<I: AsyncInterface, D: DelayNs> Access for AsyncTransport<I, D> {
+    async fn init(&mut self, dev: &mut bme680_dev) -> i8 {
+        native::code(native::init_device(dev, self).await)
+    }
+
+    async fn set_sensor_settings(&mut self, desired_settings: u16, dev: &mut bme680_dev) -> i8 {
+        native::code(native::write_settings(desired_settings, dev, self).await)
+    }
+
+    async fn set_sensor_mode(&mut self, dev: &mut bme680_dev) -> i8 {
+        native::code(native::change_mode(dev, self).await)
+    }
+
+    async fn get_sensor_data(&mut self, data: &mut bme680_field_data, dev: &mut bme680_dev) -> i8 {
+        native::code(native::read_sensor_data(data, dev, self).await)
+    }
+
+    async fn read_regs(&mut self, reg_addr: u8, reg_data: &mut [u8], dev: &mut bme680_dev) -> i8 {
+        native::code(native::get_regs(reg_addr, reg_data, dev, self).await)
+    }
+
+    async fn write_regs(&mut self, reg_addr: &[u8], reg_data: &[u8], dev: &mut bme680_dev) -> i8 {
+        native::code(native::set_regs(reg_addr, reg_data, dev, self).await)
+    }
+
+    async fn delay_ms(&mut self, period: u32) {
+        AsyncBus::delay_ms(self, period).await;
+    }
+
+    fn take_error(&mut self) -> Option<BusError> {
+        self.0.error.take()
+    }
+}
+
+///
+/// A BME680 sensor on an async bus
+///
+pub struct AsyncBME680<I, D> {
+    transport: AsyncTransport<I, D>,
+    controller: Controller,
+}
+
+impl<I2C, D> AsyncBME680<I2cInterface<I2C>, D>
+where
+    I2cInterface<I2C>: AsyncInterface,
+    D: DelayNs,
+{
+    ///
+    /// Initializes the sensor at `device_id` on an I2C bus.
+    ///
+    pub async fn new_i2c(
+        i2c: I2C,
+        device_id: Bme680Address,
+        delay: D,
+    ) -> Result<Self, SensorError> {
+        AsyncBME680::init(
+            I2cInterface::new(i2c, device_id),
+            delay,
+            bme680_intf_BME680_I2C_INTF,
+            device_id as u8,
+        )
+        .await
+    }
+}
+
+impl<SPI, D> AsyncBME680<SpiInterface<SPI, HardwareChipSelect>, D>
+where
+    SpiInterface<SPI, HardwareChipSelect>: AsyncInterface,
+    D: DelayNs,
+{
+    ///
+    /// Initializes the sensor on a SPI device, which drives the chip select itself.
+    ///
+    pub async fn new_spi(spi: SPI, delay: D) -> Result<Self, SensorError> {
+        AsyncBME680::init(
+            SpiInterface::new(spi, HardwareChipSelect),
+            delay,
+            bme680_intf_BME680_SPI_INTF,
+            0,
+        )
+        .await
+    }
+}
+
+impl<I, D> AsyncBME680<I, D>
+where
+    I: AsyncInterface,
+    D: DelayNs,
+{
+    async fn init(
+        interface: I,
+        delay: D,
+        intf: bme680_intf,
+        dev_id: u8,
+    ) -> Result<Self, SensorError> {
+        let mut sensor = AsyncBME680 {
+            transport: AsyncTransport(Transport {
+                interface,
+                delay,
+                error: None,
+            }),
+            controller: Controller::new(native::new_device(intf, dev_id)),
+        };
+        sensor.controller.init(&mut sensor.transport).await?;
+        Ok(sensor)
+    }
+
+    ///
+    /// Gives back the bus interface and the delay provider.
+    ///
+    pub fn release(self) -> (I, D) {
+        (self.transport.0.interface, self.transport.0.delay)
+    }
+
+    ///
+    /// Runs a forced measurement and waits for its result without blocking, see
+    /// `BME680::read_all`.
+    ///
+    pub async fn read_all(&mut self) -> Result<Bme680Data, SensorError> {
+        self.controller.read_all(&mut self.transport).await
+    }
+
+    ///
+    /// Measures and updates the IAQ estimate, see `BME680::read_iaq`.
+    ///
+    pub async fn read_iaq(&mut self) -> Result<Iaq, SensorError> {
+        self.controller.read_iaq(&mut self.transport).await
+    }
+
+    ///
+    /// Like `read_all`, with the time the measurement finished.
+    ///
+    #[cfg(feature = "std")]
+    pub async fn read_all_timestamped(&mut self) -> Result<Timestamped<Bme680Data>, SensorError> {
+        self.read_all().await.map(Timestamped::now)
+    }
+
+    ///
+    /// Reads the settings the sensor is actually configured with, see `BME680::read_config`.
+    ///
+    pub async fn read_config(&mut self) -> Result<Bme680Config, SensorError> {
+        self.controller.read_config(&mut self.transport).await
+    }
+
+    pub async fn read_config_registers(&mut self) -> Result<ConfigRegisters, SensorError> {
+        self.controller
+            .read_config_registers(&mut self.transport)
+            .await
+    }
+
+    ///
+    /// Measures the gas resistance at each step of the heater profile, see
+    /// `BME680::scan_heater_profile`.
+    ///
+    pub async fn scan_heater_profile(&mut self) -> Result<GasScan, SensorError> {
+        self.controller
+            .scan_heater_profile(&mut self.transport)
+            .await
+    }
+
+    controller::settings_api!();
+}
+
+#[cfg(feature = "std")]
+impl<I, D> AsyncBME680<I, D>
+where
+    I: AsyncInterface,
+    D: DelayNs,
+{
+    ///
+    /// Measures every `interval`, starting right away. Failed measurements are returned as
+    /// errors without ending the stream. If a measurement takes longer than `interval`,
+    /// the next one starts immediately.
+    ///
+    pub fn samples(
+        &mut self,
+        interval: Duration,
+    ) -> impl Stream<Item = Result<Bme680Data, SensorError>> + '_ {
+        stream::unfold(
+            (self, Cadence::new(interval)),
+            |(sensor, mut cadence)| async move {
+                let wait = cadence.wait(Instant::now());
+                delay(&mut sensor.transport.0.delay, wait).await;
+                let sample = sensor.read_all().await;
+                Some((sample, (sensor, cadence)))
+            },
+        )
+    }
+}
+
+///
+/// Waits for `wait`, in steps of up to `u32::MAX` ns.
+///
+#[cfg(feature = "std")]
+async fn delay<D: DelayNs>(delay: &mut D, mut wait: Duration) {
+    while !wait.is_zero() {
+        let ns = wait.as_nanos().min(u32::MAX as u128) as u32;
+        delay.delay_ns(ns).await;
+        wait = wait.saturating_sub(Duration::from_nanos(ns as u64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ConfigError;
+    use crate::testing::{Bme680Simulator, Environment, NoDelay};
+    use crate::{HeaterStep, Oversampling, UnstableHeaterPolicy};
+
+    type SimulatedSensor = AsyncBME680<I2cInterface<Bme680Simulator>, NoDelay>;
+
+    async fn simulated_device(simulator: Bme680Simulator) -> SimulatedSensor {
+        AsyncBME680::new_i2c(simulator, Bme680Address::Primary, NoDelay)
+            .await
+            .unwrap()
+    }
+
+    fn released(sensor: SimulatedSensor) -> Bme680Simulator {
+        sensor.release().0.release()
+    }
+
+    #[test]
+    fn read_all() {
+        futures::executor::block_on(async {
+            let mut sensor = simulated_device(Bme680Simulator::new()).await;
+            sensor.set_enable_gas_resistence(true);
+            sensor
+                .set_temperature_oversampling(Oversampling::_2X)
+                .unwrap();
+            let data = sensor.read_all().await.unwrap();
+            assert!(data.gas_resistance.is_some());
+            assert!(data.gas_valid);
+            assert!(data.heat_stable);
+            assert_eq!(
+                data.settings.temperature_oversampling,
+                sensor.get_temperature_oversampling()
+            );
+
+            let simulator = released(sensor);
+            let ctrl_meas = simulator.register(BME680_CONF_T_P_MODE_ADDR);
+            assert_eq!(ctrl_meas & BME680_OST_MSK, 2 << BME680_OST_POS);
+            assert_eq!(
+                simulator.register(BME680_CONF_ODR_RUN_GAS_NBC_ADDR) & BME680_RUN_GAS_MSK,
+                BME680_RUN_GAS_MSK
+            );
+        });
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn samples() {
+        use futures::StreamExt;
+
+        futures::executor::block_on(async {
+            let mut sensor = simulated_device(Bme680Simulator::new()).await;
+            let samples: Vec<_> = sensor
+                .samples(Duration::from_millis(1))
+                .take(2)
+                .collect()
+                .await;
+            assert_eq!(samples.len(), 2);
+            assert!(samples.iter().all(|sample| sample.is_ok()));
+        });
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn waits_are_not_truncated() {
+        #[derive(Default)]
+        struct Waited(Vec<u32>);
+
+        impl DelayNs for Waited {
+            async fn delay_ns(&mut self, ns: u32) {
+                self.0.push(ns);
+            }
+        }
+
+        futures::executor::block_on(async {
+            let mut waited = Waited::default();
+            delay(&mut waited, Duration::from_nanos(1_500)).await;
+            assert_eq!(waited.0, [1_500]);
+
+            let mut waited = Waited::default();
+            let wait = Duration::from_secs(10) + Duration::from_nanos(1);
+            delay(&mut waited, wait).await;
+            assert_eq!(waited.0.len(), 3);
+            let total: u64 = waited.0.iter().map(|&ns| ns as u64).sum();
+            assert_eq!(total, wait.as_nanos() as u64);
+        });
+    }
+
+    #[test]
+    fn unstable_heater_is_retried() {
+        futures::executor::block_on(async {
+            let mut simulator = Bme680Simulator::new();
+            simulator.set_heater_stable(false);
+            let mut sensor = simulated_device(simulator).await;
+            sensor.set_enable_gas_resistence(true);
+            sensor.set_unstable_heater_policy(UnstableHeaterPolicy::Retry(3));
+            let data = sensor.read_all().await.unwrap();
+            assert!(data.gas_valid);
+            assert!(!data.heat_stable);
+            assert_eq!(data.gas_resistance, None);
+            assert_eq!(released(sensor).measurements(), 4);
+        });
+    }
+
+    #[test]
+    fn init_checks_chip_id() {
+        futures::executor::block_on(async {
+            let mut simulator = Bme680Simulator::new();
+            simulator.set_register(BME680_CHIP_ID_ADDR, 0);
+            assert!(matches!(
+                AsyncBME680::new_i2c(simulator, Bme680Address::Primary, NoDelay).await,
+                Err(SensorError::DeviceNotFound)
+            ));
+        });
+    }
+
+    #[test]
+    fn read_over_spi() {
+        futures::executor::block_on(async {
+            let mut simulator = Bme680Simulator::new_spi();
+            simulator.set_environment(Environment {
+                temperature: 23.5,
+                ..Environment::default()
+            });
+            let mut sensor = AsyncBME680::new_spi(simulator, NoDelay).await.unwrap();
+            assert_eq!(sensor.calibration(), crate::testing::typical_calibration());
+            sensor
+                .set_temperature_oversampling(Oversampling::_2X)
+                .unwrap();
+            let data = sensor.read_all().await.unwrap();
+            assert!((data.temperature - 23.5).abs() < 0.05);
+        });
+    }
+
+    #[test]
+    fn read_simulated_environment() {
+        futures::executor::block_on(async {
+            let mut simulator = Bme680Simulator::new();
+            simulator.set_environment(Environment {
+                humidity: 60.0,
+                ..Environment::default()
+            });
+            let mut sensor = simulated_device(simulator).await;
+            // humidity is compensated with the temperature
+            sensor
+                .set_temperature_oversampling(Oversampling::_1X)
+                .unwrap();
+            sensor.set_humidity_oversampling(Oversampling::_1X).unwrap();
+            let data = sensor.read_all().await.unwrap();
+            assert!((data.humidity - 60.0).abs() < 0.2);
+
+            let config = sensor.read_config().await.unwrap();
+            assert_eq!(config.humidity_oversampling, Oversampling::_1X);
+            assert_eq!(config.pressure_oversampling, Oversampling::None);
+        });
+    }
+
+    #[test]
+    fn writes_are_verified() {
+        futures::executor::block_on(async {
+            let mut simulator = Bme680Simulator::new();
+            simulator.lock_register(BME680_CONF_OS_H_ADDR);
+            let mut sensor = simulated_device(simulator).await;
+            sensor.set_verify_writes(true);
+            sensor
+                .set_temperature_oversampling(Oversampling::_1X)
+                .unwrap();
+            sensor.set_humidity_oversampling(Oversampling::_2X).unwrap();
+            assert!(matches!(
+                sensor.read_all().await,
+                Err(SensorError::VerificationFailed {
+                    register: BME680_CONF_OS_H_ADDR,
+                    expected: 2,
+                    actual: 0,
+                })
+            ));
+        });
+    }
+
+    #[test]
+    fn heater_profile_is_scanned() {
+        futures::executor::block_on(async {
+            let mut sensor = simulated_device(Bme680Simulator::new()).await;
+            let steps = [
+                HeaterStep {
+                    temperature: 200,
+                    duration: 100,
+                },
+                HeaterStep {
+                    temperature: 300,
+                    duration: 150,
+                },
+            ];
+            sensor.set_heater_profile(&steps).unwrap();
+            assert!(matches!(
+                sensor.scan_heater_profile().await,
+                Err(SensorError::InvalidConfiguration(ConfigError::Requires {
+                    requirement: "gas measurements",
+                    ..
+                }))
+            ));
+
+            sensor.set_enable_gas_resistence(true);
+            let scan = sensor.scan_heater_profile().await.unwrap();
+            assert_eq!(scan.readings().len(), steps.len());
+            for (i, reading) in scan.readings().iter().enumerate() {
+                assert_eq!(reading.step, steps[i]);
+                assert_eq!(reading.gas_index, i as u8);
+                assert!(reading.gas_resistance.is_some());
+            }
+            assert!(sensor.read_iaq().await.is_ok());
+        });
+    }
+}
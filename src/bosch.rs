@@ -0,0 +1,163 @@
+use crate::compensation::CalibrationData;
+use crate::source::*;
+use crate::Bus;
+
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::ptr;
+use std::slice;
+
+// Points to a `&mut dyn Bus` on the stack of `with_bus` while a driver call is running
+thread_local!(static BUS: Cell<*mut c_void> = const { Cell::new(ptr::null_mut()) });
+
+///
+/// Routes the driver callbacks to `bus` for the duration of `f`.
+///
+fn with_bus<T>(mut bus: &mut dyn Bus, f: impl FnOnce() -> T) -> T {
+    struct Restore(*mut c_void);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            BUS.with(|current| current.set(self.0));
+        }
+    }
+
+    let bus_ptr = &mut bus as *mut &mut dyn Bus as *mut c_void;
+    let _restore = Restore(BUS.with(|current| current.replace(bus_ptr)));
+    f()
+}
+
+unsafe fn on_bus<T>(f: impl FnOnce(&mut dyn Bus) -> T) -> Option<T> {
+    let bus = BUS.with(|current| current.get()) as *mut &mut dyn Bus;
+    bus.as_mut().map(|bus| f(&mut **bus))
+}
+
+unsafe extern "C" fn write(_dev_id: u8, reg_addr: u8, data: *mut u8, len: u16) -> i8 {
+    let d = slice::from_raw_parts(data, len as usize);
+    on_bus(|bus| bus.write(reg_addr, d)).unwrap_or(1)
+}
+
+unsafe extern "C" fn read(_dev_id: u8, reg_addr: u8, data: *mut u8, len: u16) -> i8 {
+    let d = slice::from_raw_parts_mut(data, len as usize);
+    on_bus(|bus| bus.read(reg_addr, d)).unwrap_or(1)
+}
+
+unsafe extern "C" fn delay(ms: u32) {
+    on_bus(|bus| bus.delay_ms(ms));
+}
+
+pub(crate) fn calibration(dev: &bme680_dev) -> CalibrationData {
+    let calib = &dev.calib;
+    CalibrationData {
+        par_h1: calib.par_h1,
+        par_h2: calib.par_h2,
+        par_h3: calib.par_h3,
+        par_h4: calib.par_h4,
+        par_h5: calib.par_h5,
+        par_h6: calib.par_h6,
+        par_h7: calib.par_h7,
+        par_gh1: calib.par_gh1,
+        par_gh2: calib.par_gh2,
+        par_gh3: calib.par_gh3,
+        par_t1: calib.par_t1,
+        par_t2: calib.par_t2,
+        par_t3: calib.par_t3,
+        par_p1: calib.par_p1,
+        par_p2: calib.par_p2,
+        par_p3: calib.par_p3,
+        par_p4: calib.par_p4,
+        par_p5: calib.par_p5,
+        par_p6: calib.par_p6,
+        par_p7: calib.par_p7,
+        par_p8: calib.par_p8,
+        par_p9: calib.par_p9,
+        par_p10: calib.par_p10,
+        res_heat_range: calib.res_heat_range,
+        res_heat_val: calib.res_heat_val,
+        range_sw_err: calib.range_sw_err,
+    }
+}
+
+pub(crate) fn new_device(intf: bme680_intf, dev_id: u8) -> bme680_dev {
+    bme680_dev {
+        chip_id: BME680_CHIP_ID,
+        dev_id, // i2c address, unused for SPI
+        intf,
+        mem_page: 0,
+        amb_temp: 25, // according to specs
+        calib: bme680_calib_data::default(),
+        tph_sett: bme680_tph_sett::default(),
+        gas_sett: bme680_gas_sett::default(),
+        power_mode: BME680_SLEEP_MODE, // sleep mode, 0x01 forced mode,
+        new_fields: 0,
+        info_msg: 0,
+        read: Some(read),
+        write: Some(write),
+        delay_ms: Some(delay),
+        com_rslt: 0,
+    }
+}
+
+pub(crate) fn init(dev: &mut bme680_dev, bus: &mut dyn Bus) -> i8 {
+    with_bus(bus, || unsafe { bme680_init(dev) })
+}
+
+pub(crate) fn set_sensor_mode(dev: &mut bme680_dev, bus: &mut dyn Bus) -> i8 {
+    with_bus(bus, || unsafe { bme680_set_sensor_mode(dev) })
+}
+
+pub(crate) fn set_sensor_settings(
+    desired_settings: u16,
+    dev: &mut bme680_dev,
+    bus: &mut dyn Bus,
+) -> i8 {
+    with_bus(bus, || unsafe {
+        bme680_set_sensor_settings(desired_settings, dev)
+    })
+}
+
+pub(crate) fn write_regs(
+    reg_addr: &[u8],
+    reg_data: &[u8],
+    dev: &mut bme680_dev,
+    bus: &mut dyn Bus,
+) -> i8 {
+    if reg_addr.len() != reg_data.len() {
+        return BME680_E_INVALID_LENGTH;
+    }
+    with_bus(bus, || unsafe {
+        bme680_set_regs(
+            reg_addr.as_ptr(),
+            reg_data.as_ptr(),
+            reg_addr.len() as u8,
+            dev,
+        )
+    })
+}
+
+pub(crate) fn read_regs(
+    reg_addr: u8,
+    reg_data: &mut [u8],
+    dev: &mut bme680_dev,
+    bus: &mut dyn Bus,
+) -> i8 {
+    with_bus(bus, || unsafe {
+        bme680_get_regs(reg_addr, reg_data.as_mut_ptr(), reg_data.len() as u16, dev)
+    })
+}
+
+pub(crate) fn get_profile_dur(dev: &bme680_dev) -> u16 {
+    let mut duration = 0;
+    unsafe {
+        bme680_get_profile_dur(&mut duration, dev);
+    }
+    duration
+}
+
+pub(crate) fn get_sensor_data(
+    data: &mut bme680_field_data,
+    dev: &mut bme680_dev,
+    bus: &mut dyn Bus,
+) -> i8 {
+    with_bus(bus, || unsafe { bme680_get_sensor_data(data, dev) })
+}
@@ -0,0 +1,463 @@
+//!
+//! Compensation of the sensor's raw ADC values, following the formulas of Bosch's driver.
+//!
+//! The integer functions produce the same values as the driver does by default, except for
+//! pressures the driver's 32 bit arithmetic overflows on; the `_f32` functions are the
+//! floating point variants given in the datasheet.
+//!
+use crate::source::*;
+
+/// Look up table 1 for the possible gas range values
+const LOOKUP_TABLE1: [u32; 16] = [
+    2147483647, 2147483647, 2147483647, 2147483647, 2147483647, 2126008810, 2147483647, 2130303777,
+    2147483647, 2147483647, 2143188679, 2136746228, 2147483647, 2126008810, 2147483647, 2147483647,
+];
+
+/// Look up table 2 for the possible gas range values
+const LOOKUP_TABLE2: [u32; 16] = [
+    4096000000, 2048000000, 1024000000, 512000000, 255744255, 127110228, 64000000, 32258064,
+    16016016, 8000000, 4000000, 2000000, 1000000, 500000, 250000, 125000,
+];
+
+/// Look up table k1 for the possible gas range values, floating point variant
+const LOOKUP_K1_RANGE: [f32; 16] = [
+    0.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, -0.8, 0.0, 0.0, -0.2, -0.5, 0.0, -1.0, 0.0, 0.0,
+];
+
+/// Look up table k2 for the possible gas range values, floating point variant
+const LOOKUP_K2_RANGE: [f32; 16] = [
+    0.0, 0.0, 0.0, 0.0, 0.1, 0.7, 0.0, -0.8, -0.1, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+];
+
+const BME680_MAX_OVERFLOW_VAL: i32 = 0x4000_0000;
+
+///
+/// Calibration parameters, as programmed into each sensor by the manufacturer
+///
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct CalibrationData {
+    pub par_h1: u16,
+    pub par_h2: u16,
+    pub par_h3: i8,
+    pub par_h4: i8,
+    pub par_h5: i8,
+    pub par_h6: u8,
+    pub par_h7: i8,
+    pub par_gh1: i8,
+    pub par_gh2: i16,
+    pub par_gh3: i8,
+    pub par_t1: u16,
+    pub par_t2: i16,
+    pub par_t3: i8,
+    pub par_p1: u16,
+    pub par_p2: i16,
+    pub par_p3: i8,
+    pub par_p4: i16,
+    pub par_p5: i16,
+    pub par_p6: i8,
+    pub par_p7: i8,
+    pub par_p8: i16,
+    pub par_p9: i16,
+    pub par_p10: u8,
+    pub res_heat_range: u8,
+    pub res_heat_val: i8,
+    pub range_sw_err: i8,
+}
+
+fn concat_bytes(msb: u8, lsb: u8) -> u16 {
+    (msb as u16) << 8 | lsb as u16
+}
+
+impl CalibrationData {
+    ///
+    /// Parses the calibration data from the raw register contents: the coefficient blocks at
+    /// `BME680_COEFF_ADDR1` and `BME680_COEFF_ADDR2` (in that order) and the registers at
+    /// `BME680_ADDR_RES_HEAT_RANGE_ADDR`, `BME680_ADDR_RES_HEAT_VAL_ADDR` and
+    /// `BME680_ADDR_RANGE_SW_ERR_ADDR`.
+    ///
+    pub fn from_registers(
+        coeff_array: &[u8; BME680_COEFF_SIZE as usize],
+        res_heat_range: u8,
+        res_heat_val: u8,
+        range_sw_err: u8,
+    ) -> CalibrationData {
+        CalibrationData {
+            /* Temperature related coefficients */
+            par_t1: concat_bytes(
+                coeff_array[BME680_T1_MSB_REG],
+                coeff_array[BME680_T1_LSB_REG],
+            ),
+            par_t2: concat_bytes(
+                coeff_array[BME680_T2_MSB_REG],
+                coeff_array[BME680_T2_LSB_REG],
+            ) as i16,
+            par_t3: coeff_array[BME680_T3_REG] as i8,
+
+            /* Pressure related coefficients */
+            par_p1: concat_bytes(
+                coeff_array[BME680_P1_MSB_REG],
+                coeff_array[BME680_P1_LSB_REG],
+            ),
+            par_p2: concat_bytes(
+                coeff_array[BME680_P2_MSB_REG],
+                coeff_array[BME680_P2_LSB_REG],
+            ) as i16,
+            par_p3: coeff_array[BME680_P3_REG] as i8,
+            par_p4: concat_bytes(
+                coeff_array[BME680_P4_MSB_REG],
+                coeff_array[BME680_P4_LSB_REG],
+            ) as i16,
+            par_p5: concat_bytes(
+                coeff_array[BME680_P5_MSB_REG],
+                coeff_array[BME680_P5_LSB_REG],
+            ) as i16,
+            par_p6: coeff_array[BME680_P6_REG] as i8,
+            par_p7: coeff_array[BME680_P7_REG] as i8,
+            par_p8: concat_bytes(
+                coeff_array[BME680_P8_MSB_REG],
+                coeff_array[BME680_P8_LSB_REG],
+            ) as i16,
+            par_p9: concat_bytes(
+                coeff_array[BME680_P9_MSB_REG],
+                coeff_array[BME680_P9_LSB_REG],
+            ) as i16,
+            par_p10: coeff_array[BME680_P10_REG],
+
+            /* Humidity related coefficients */
+            par_h1: (coeff_array[BME680_H1_MSB_REG] as u16) << BME680_HUM_REG_SHIFT_VAL
+                | (coeff_array[BME680_H1_LSB_REG] & BME680_BIT_H1_DATA_MSK) as u16,
+            par_h2: (coeff_array[BME680_H2_MSB_REG] as u16) << BME680_HUM_REG_SHIFT_VAL
+                | (coeff_array[BME680_H2_LSB_REG] >> BME680_HUM_REG_SHIFT_VAL) as u16,
+            par_h3: coeff_array[BME680_H3_REG] as i8,
+            par_h4: coeff_array[BME680_H4_REG] as i8,
+            par_h5: coeff_array[BME680_H5_REG] as i8,
+            par_h6: coeff_array[BME680_H6_REG],
+            par_h7: coeff_array[BME680_H7_REG] as i8,
+
+            /* Gas heater related coefficients */
+            par_gh1: coeff_array[BME680_GH1_REG] as i8,
+            par_gh2: concat_bytes(
+                coeff_array[BME680_GH2_MSB_REG],
+                coeff_array[BME680_GH2_LSB_REG],
+            ) as i16,
+            par_gh3: coeff_array[BME680_GH3_REG] as i8,
+
+            /* Other coefficients */
+            res_heat_range: (res_heat_range & BME680_RHRANGE_MSK) / 16,
+            res_heat_val: res_heat_val as i8,
+            range_sw_err: (range_sw_err as i8 & BME680_RSERROR_MSK as i8) / 16,
+        }
+    }
+}
+
+///
+/// Compensated temperature in 0.01 °C, together with the fine resolution temperature
+/// (`t_fine`) that pressure and humidity compensation depend on.
+///
+/// `temp_adc` is a 20 bit ADC reading, both values saturate for larger readings.
+///
+pub fn calc_temperature(temp_adc: u32, calib: &CalibrationData) -> (i16, i32) {
+    let var1 = ((temp_adc as i64) >> 3) - ((calib.par_t1 as i64) << 1);
+    let var2 = (var1 * calib.par_t2 as i64) >> 11;
+    let var3 = ((var1 >> 1) * (var1 >> 1)) >> 12;
+    let var3 = (var3 * ((calib.par_t3 as i64) << 4)) >> 14;
+    // saturated, only readings far beyond the operating range get near the limits
+    let t_fine = (var2 + var3).clamp(i32::MIN as i64, i32::MAX as i64);
+    let temperature = ((t_fine * 5 + 128) >> 8).clamp(i16::MIN as i64, i16::MAX as i64);
+    (temperature as i16, t_fine as i32)
+}
+
+///
+/// Compensated pressure in Pa.
+///
+/// `pres_adc` is a 20 bit ADC reading and `t_fine` comes from `calc_temperature`. Any values
+/// can be passed, but only readings within the sensor's operating range (300 to 1100 hPa at
+/// -40 to 85 °C) give meaningful pressures; other readings saturate in `0..=u32::MAX`.
+///
+pub fn calc_pressure(pres_adc: u32, t_fine: i32, calib: &CalibrationData) -> u32 {
+    // computed in i64, so readings outside of the operating range can't overflow
+    let mut var1 = ((t_fine as i64) >> 1) - 64000;
+    let mut var2 = ((((var1 >> 2) * (var1 >> 2)) >> 11) * calib.par_p6 as i64) >> 2;
+    var2 += (var1 * calib.par_p5 as i64) << 1;
+    var2 = (var2 >> 2) + ((calib.par_p4 as i64) << 16);
+    var1 = (((((var1 >> 2) * (var1 >> 2)) >> 13) * ((calib.par_p3 as i64) << 5)) >> 3)
+        + ((calib.par_p2 as i64 * var1) >> 1);
+    var1 >>= 18;
+    var1 = ((32768 + var1) * calib.par_p1 as i64) >> 15;
+    if var1 == 0 {
+        /* Avoid division by zero */
+        return 0;
+    }
+    let mut pressure_comp = (1048576 - pres_adc as i64 - (var2 >> 12)) * 3125;
+    // rounded like the C driver, which divides first to stay within 32 bits
+    if pressure_comp >= BME680_MAX_OVERFLOW_VAL as i64 {
+        pressure_comp = (pressure_comp / var1) << 1;
+    } else {
+        pressure_comp = (pressure_comp << 1) / var1;
+    }
+    // far beyond the operating range, keeps the polynomial below within 64 bits
+    let pressure_comp = pressure_comp.clamp(-(1 << 26), 1 << 26);
+    var1 = (calib.par_p9 as i64 * (((pressure_comp >> 3) * (pressure_comp >> 3)) >> 13)) >> 12;
+    var2 = ((pressure_comp >> 2) * calib.par_p8 as i64) >> 13;
+    let var3 =
+        ((pressure_comp >> 8) * (pressure_comp >> 8) * (pressure_comp >> 8) * calib.par_p10 as i64)
+            >> 17;
+
+    let pressure_comp = pressure_comp + ((var1 + var2 + var3 + ((calib.par_p7 as i64) << 7)) >> 4);
+    pressure_comp.clamp(0, u32::MAX as i64) as u32
+}
+
+///
+/// Compensated relative humidity in 0.001 %.
+///
+pub fn calc_humidity(hum_adc: u16, t_fine: i32, calib: &CalibrationData) -> u32 {
+    let temp_scaled = (t_fine * 5 + 128) >> 8;
+    let var1 = (hum_adc as i32 - calib.par_h1 as i32 * 16)
+        - (((temp_scaled * calib.par_h3 as i32) / 100) >> 1);
+    let var2 = (calib.par_h2 as i32
+        * (((temp_scaled * calib.par_h4 as i32) / 100)
+            + (((temp_scaled * ((temp_scaled * calib.par_h5 as i32) / 100)) >> 6) / 100)
+            + (1 << 14)))
+        >> 10;
+    // widened, so extreme readings saturate instead of overflowing
+    let var3 = var1 as i64 * var2 as i64;
+    let var4 = (calib.par_h6 as i64) << 7;
+    let var4 = (var4 + ((temp_scaled * calib.par_h7 as i32) / 100) as i64) >> 4;
+    let var5 = ((var3 >> 14) * (var3 >> 14)) >> 10;
+    let var6 = (var4 * var5) >> 1;
+    let calc_hum = (((var3 + var6) >> 10) * 1000) >> 12;
+
+    /* Cap at 100%rH */
+    calc_hum.clamp(0, 100000) as u32
+}
+
+///
+/// Compensated gas resistance in Ohms.
+///
+pub fn calc_gas_resistance(gas_res_adc: u16, gas_range: u8, calib: &CalibrationData) -> u32 {
+    let gas_range = (gas_range & BME680_GAS_RANGE_MSK) as usize;
+    let var1 = ((1340 + 5 * calib.range_sw_err as i64) * LOOKUP_TABLE1[gas_range] as i64) >> 16;
+    let var2 = ((gas_res_adc as i64) << 15) - 16777216 + var1;
+    if var2 == 0 {
+        /* Avoid division by zero */
+        return 0;
+    }
+    let var3 = (LOOKUP_TABLE2[gas_range] as i64 * var1) >> 9;
+    ((var3 + (var2 >> 1)) / var2) as u32
+}
+
+///
+/// Heater resistance register value for a target temperature in °C (capped at 400 °C) and the
+/// ambient temperature in °C.
+///
+pub fn calc_heater_res(temp: u16, amb_temp: i8, calib: &CalibrationData) -> u8 {
+    let temp = temp.min(400) as i32; /* Cap temperature */
+
+    let var1 = ((amb_temp as i32 * calib.par_gh3 as i32) / 1000) * 256;
+    let var2 = (calib.par_gh1 as i32 + 784)
+        * (((((calib.par_gh2 as i32 + 154009) * temp * 5) / 100) + 3276800) / 10);
+    let var3 = var1 + (var2 / 2);
+    let var4 = var3 / (calib.res_heat_range as i32 + 4);
+    let var5 = (131 * calib.res_heat_val as i32) + 65536;
+    let heatr_res_x100 = ((var4 / var5) - 250) * 34;
+    ((heatr_res_x100 + 50) / 100) as u8
+}
+
+///
+/// Gas wait register value for a heating duration in ms (capped at 4032 ms).
+///
+pub fn calc_heater_dur(dur: u16) -> u8 {
+    if dur >= 0xfc0 {
+        0xff /* Max duration*/
+    } else {
+        let mut dur = dur;
+        let mut factor = 0_u8;
+        while dur > 0x3f {
+            dur /= 4;
+            factor += 1;
+        }
+        dur as u8 + factor * 64
+    }
+}
+
+///
+/// Compensated temperature in °C, together with the fine resolution temperature (`t_fine`)
+/// that pressure and humidity compensation depend on.
+///
+pub fn calc_temperature_f32(temp_adc: u32, calib: &CalibrationData) -> (f32, f32) {
+    let var1 = ((temp_adc as f32 / 16384.0) - (calib.par_t1 as f32 / 1024.0)) * calib.par_t2 as f32;
+    let var2 = ((temp_adc as f32 / 131072.0) - (calib.par_t1 as f32 / 8192.0))
+        * ((temp_adc as f32 / 131072.0) - (calib.par_t1 as f32 / 8192.0))
+        * (calib.par_t3 as f32 * 16.0);
+    let t_fine = var1 + var2;
+    (t_fine / 5120.0, t_fine)
+}
+
+///
+/// Compensated pressure in Pa.
+///
+pub fn calc_pressure_f32(pres_adc: u32, t_fine: f32, calib: &CalibrationData) -> f32 {
+    let mut var1 = (t_fine / 2.0) - 64000.0;
+    let mut var2 = var1 * var1 * (calib.par_p6 as f32 / 131072.0);
+    var2 += var1 * calib.par_p5 as f32 * 2.0;
+    var2 = (var2 / 4.0) + (calib.par_p4 as f32 * 65536.0);
+    var1 =
+        (((calib.par_p3 as f32 * var1 * var1) / 16384.0) + (calib.par_p2 as f32 * var1)) / 524288.0;
+    var1 = (1.0 + (var1 / 32768.0)) * calib.par_p1 as f32;
+    if var1 as i32 == 0 {
+        /* Avoid exception caused by division by zero */
+        return 0.0;
+    }
+    let mut calc_pres = 1048576.0 - pres_adc as f32;
+    calc_pres = ((calc_pres - (var2 / 4096.0)) * 6250.0) / var1;
+    var1 = (calib.par_p9 as f32 * calc_pres * calc_pres) / 2147483648.0;
+    var2 = calc_pres * (calib.par_p8 as f32 / 32768.0);
+    let var3 = (calc_pres / 256.0)
+        * (calc_pres / 256.0)
+        * (calc_pres / 256.0)
+        * (calib.par_p10 as f32 / 131072.0);
+    calc_pres + (var1 + var2 + var3 + (calib.par_p7 as f32 * 128.0)) / 16.0
+}
+
+///
+/// Compensated relative humidity in %.
+///
+pub fn calc_humidity_f32(hum_adc: u16, t_fine: f32, calib: &CalibrationData) -> f32 {
+    let temp_comp = t_fine / 5120.0;
+    let var1 =
+        hum_adc as f32 - ((calib.par_h1 as f32 * 16.0) + ((calib.par_h3 as f32 / 2.0) * temp_comp));
+    let var2 = var1
+        * ((calib.par_h2 as f32 / 262144.0)
+            * (1.0
+                + ((calib.par_h4 as f32 / 16384.0) * temp_comp)
+                + ((calib.par_h5 as f32 / 1048576.0) * temp_comp * temp_comp)));
+    let var3 = calib.par_h6 as f32 / 16384.0;
+    let var4 = calib.par_h7 as f32 / 2097152.0;
+    let calc_hum = var2 + ((var3 + (var4 * temp_comp)) * var2 * var2);
+
+    /* Cap at 100%rH */
+    calc_hum.clamp(0.0, 100.0)
+}
+
+///
+/// Compensated gas resistance in Ohms.
+///
+pub fn calc_gas_resistance_f32(gas_res_adc: u16, gas_range: u8, calib: &CalibrationData) -> f32 {
+    let gas_range = (gas_range & BME680_GAS_RANGE_MSK) as usize;
+    let var1 = 1340.0 + (5.0 * calib.range_sw_err as f32);
+    let var2 = var1 * (1.0 + LOOKUP_K1_RANGE[gas_range] / 100.0);
+    let var3 = 1.0 + (LOOKUP_K2_RANGE[gas_range] / 100.0);
+    1.0 / (var3
+        * 0.000000125
+        * (1_u32 << gas_range) as f32
+        * (((gas_res_adc as f32 - 512.0) / var2) + 1.0))
+}
+
+///
+/// Heater resistance register value for a target temperature in °C (capped at 400 °C) and the
+/// ambient temperature in °C, floating point variant.
+///
+pub fn calc_heater_res_f32(temp: u16, amb_temp: i8, calib: &CalibrationData) -> u8 {
+    let temp = temp.min(400); /* Cap temperature */
+
+    let var1 = (calib.par_gh1 as f32 / 16.0) + 49.0;
+    let var2 = ((calib.par_gh2 as f32 / 32768.0) * 0.0005) + 0.00235;
+    let var3 = calib.par_gh3 as f32 / 1024.0;
+    let var4 = var1 * (1.0 + (var2 * temp as f32));
+    let var5 = var4 + (var3 * amb_temp as f32);
+    (3.4 * ((var5
+        * (4.0 / (4.0 + calib.res_heat_range as f32))
+        * (1.0 / (1.0 + (calib.res_heat_val as f32 * 0.002))))
+        - 25.0)) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::typical_calibration;
+
+    #[test]
+    fn integer_and_float_variants_agree() {
+        let calib = typical_calibration();
+        let (temperature, t_fine) = calc_temperature(500_000, &calib);
+        let (temperature_f32, t_fine_f32) = calc_temperature_f32(500_000, &calib);
+        assert!((temperature as f32 / 100.0 - temperature_f32).abs() < 0.05);
+
+        let pressure = calc_pressure(400_000, t_fine, &calib);
+        let pressure_f32 = calc_pressure_f32(400_000, t_fine_f32, &calib);
+        assert!((pressure as f32 - pressure_f32).abs() < 10.0);
+
+        let humidity = calc_humidity(20_000, t_fine, &calib);
+        let humidity_f32 = calc_humidity_f32(20_000, t_fine_f32, &calib);
+        assert!((humidity as f32 / 1000.0 - humidity_f32).abs() < 0.1);
+
+        let gas = calc_gas_resistance(600, 5, &calib);
+        let gas_f32 = calc_gas_resistance_f32(600, 5, &calib);
+        assert!((gas as f32 - gas_f32).abs() / gas_f32 < 0.01);
+
+        let res_heat = calc_heater_res(320, 25, &calib);
+        let res_heat_f32 = calc_heater_res_f32(320, 25, &calib);
+        assert!((res_heat as i16 - res_heat_f32 as i16).abs() <= 1);
+    }
+
+    #[test]
+    fn humidity_is_capped() {
+        let calib = typical_calibration();
+        let (_, t_fine) = calc_temperature(500_000, &calib);
+        assert_eq!(calc_humidity(0, t_fine, &calib), 0);
+        assert_eq!(calc_humidity(u16::MAX, t_fine, &calib), 100_000);
+        assert_eq!(calc_humidity_f32(u16::MAX, t_fine as f32, &calib), 100.0);
+    }
+
+    #[test]
+    fn pressure_at_adc_extremes() {
+        let calib = typical_calibration();
+        for temp_adc in [0, 266_719, 500_000, 728_856, 0xfffff, u32::MAX] {
+            let (_, t_fine) = calc_temperature(temp_adc, &calib);
+            for pres_adc in [0, 400_000, 0xfffff, u32::MAX] {
+                calc_pressure(pres_adc, t_fine, &calib);
+            }
+        }
+        for t_fine in [i32::MIN, 0, i32::MAX] {
+            calc_pressure(0xfffff, t_fine, &calib);
+        }
+
+        // lower ADC readings for higher pressures
+        let (_, t_fine) = calc_temperature(500_000, &calib);
+        let highest = calc_pressure(0, t_fine, &calib);
+        let lowest = calc_pressure(0xfffff, t_fine, &calib);
+        assert!(lowest < 92847 && 92847 < highest);
+        assert_eq!(calc_pressure(400_000, t_fine, &calib), 92847);
+        // the C driver overflows here and reports 2048 Pa less
+        let (_, t_fine) = calc_temperature(600_000, &calib);
+        let (_, t_fine_f32) = calc_temperature_f32(600_000, &calib);
+        assert_eq!(calc_pressure(300_000, t_fine, &calib), 115841);
+        assert!((115841.0 - calc_pressure_f32(300_000, t_fine_f32, &calib)).abs() < 10.0);
+    }
+
+    #[test]
+    fn heater_duration() {
+        assert_eq!(calc_heater_dur(63), 63);
+        assert_eq!(calc_heater_dur(150), 101);
+        assert_eq!(calc_heater_dur(0xfc0), 0xff);
+    }
+
+    #[test]
+    fn parse_calibration_registers() {
+        let mut coeff_array = [0_u8; BME680_COEFF_SIZE as usize];
+        coeff_array[BME680_T1_LSB_REG] = 0x12;
+        coeff_array[BME680_T1_MSB_REG] = 0x66;
+        coeff_array[BME680_H1_LSB_REG] = 0x3f;
+        coeff_array[BME680_H1_MSB_REG] = 0x30;
+        coeff_array[BME680_GH2_LSB_REG] = 0xaf;
+        coeff_array[BME680_GH2_MSB_REG] = 0xe8;
+        let calib = CalibrationData::from_registers(&coeff_array, 0x16, 0x2e, 0xf0);
+        assert_eq!(calib.par_t1, 0x6612);
+        assert_eq!(calib.par_h1, 0x30f);
+        assert_eq!(calib.par_h2, 0x3);
+        assert_eq!(calib.par_gh2, -5969);
+        assert_eq!(calib.res_heat_range, 1);
+        assert_eq!(calib.res_heat_val, 46);
+        assert_eq!(calib.range_sw_err, -1);
+    }
+}
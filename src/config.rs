@@ -0,0 +1,550 @@
+//!
+//! All settings of a sensor as a single value.
+//!
+//! ```
+//! use bme680::config::Bme680Config;
+//! use bme680::{FilterSize, Oversampling};
+//!
+//! let config = Bme680Config::builder()
+//!     .temperature_oversampling(Oversampling::_2X)
+//!     .humidity_oversampling(Oversampling::_1X)
+//!     .filter(FilterSize::Size3)
+//!     .gas(true)
+//!     .heater_temperature(300)
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(config.heater_duration, 150);
+//! ```
+//!
+
+use crate::compensation::{calc_heater_dur, calc_heater_res, CalibrationData};
+use crate::errors::{ConfigError, SensorError};
+use crate::source::*;
+use crate::{
+    check_heater_step, driver, FilterSize, Oversampling, HEATER_DURATION_DEFAULT,
+    HEATER_TEMPERATURE_DEFAULT, HEATER_TEMPERATURE_MAX, HEATER_TEMPERATURE_MIN,
+};
+
+use core::fmt;
+use core::str::FromStr;
+use core::time::Duration;
+
+///
+/// Oversampling, filter and heater settings of a sensor
+///
+/// The default is what a sensor starts with: no measurements, no filter, no gas
+/// measurement and the heater enabled at 320°C for 150 ms.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Bme680Config {
+    pub temperature_oversampling: Oversampling,
+    pub pressure_oversampling: Oversampling,
+    pub humidity_oversampling: Oversampling,
+    pub filter: FilterSize,
+    pub gas_enabled: bool,
+    pub heater_enabled: bool,
+    /// Heater target temperature in °C
+    pub heater_temperature: u16,
+    /// Heating duration in ms
+    pub heater_duration: u16,
+}
+
+impl Default for Bme680Config {
+    fn default() -> Self {
+        Bme680Config {
+            temperature_oversampling: Oversampling::None,
+            pressure_oversampling: Oversampling::None,
+            humidity_oversampling: Oversampling::None,
+            filter: FilterSize::Size0,
+            gas_enabled: false,
+            heater_enabled: true,
+            heater_temperature: HEATER_TEMPERATURE_DEFAULT,
+            heater_duration: HEATER_DURATION_DEFAULT,
+        }
+    }
+}
+
+impl Bme680Config {
+    pub fn builder() -> Bme680ConfigBuilder {
+        Bme680ConfigBuilder::default()
+    }
+
+    ///
+    /// Time a forced measurement with this configuration takes, including heating.
+    ///
+    pub fn measurement_duration(&self) -> Duration {
+        let mut dev = driver::new_device(bme680_intf_BME680_I2C_INTF, 0);
+        self.write_to(&mut dev);
+        Duration::from_millis(driver::get_profile_dur(&dev) as u64)
+    }
+
+    ///
+    /// Checks the heater settings against the sensor's limits, and that pressure and
+    /// humidity are only measured together with the temperature they are compensated with.
+    ///
+    pub fn validate(&self) -> Result<(), SensorError> {
+        Ok(self.check()?)
+    }
+
+    pub(crate) fn check(&self) -> Result<(), ConfigError> {
+        check_heater_step(self.heater_temperature, self.heater_duration)?;
+        self.check_oversampling()
+    }
+
+    pub(crate) fn check_oversampling(&self) -> Result<(), ConfigError> {
+        let compensated = [
+            ("pressure_oversampling", self.pressure_oversampling),
+            ("humidity_oversampling", self.humidity_oversampling),
+        ];
+        for (setting, oversampling) in compensated {
+            if oversampling != Oversampling::None
+                && self.temperature_oversampling == Oversampling::None
+            {
+                return Err(ConfigError::Requires {
+                    setting,
+                    requirement: "temperature_oversampling",
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn from_device(dev: &bme680_dev) -> Self {
+        // the chip treats the unused oversampling values as 16x
+        let oversampling = |value| Oversampling::from_register(value).unwrap_or(Oversampling::_16X);
+        Bme680Config {
+            temperature_oversampling: oversampling(dev.tph_sett.os_temp),
+            pressure_oversampling: oversampling(dev.tph_sett.os_pres),
+            humidity_oversampling: oversampling(dev.tph_sett.os_hum),
+            filter: FilterSize::from_register(dev.tph_sett.filter).unwrap_or(FilterSize::Size127),
+            gas_enabled: dev.gas_sett.run_gas == BME680_ENABLE_GAS_MEAS,
+            heater_enabled: dev.gas_sett.heatr_ctrl == BME680_ENABLE_HEATER,
+            heater_temperature: dev.gas_sett.heatr_temp,
+            heater_duration: dev.gas_sett.heatr_dur,
+        }
+    }
+
+    pub(crate) fn write_to(&self, dev: &mut bme680_dev) {
+        dev.tph_sett.os_temp = self.temperature_oversampling.to_register();
+        dev.tph_sett.os_pres = self.pressure_oversampling.to_register();
+        dev.tph_sett.os_hum = self.humidity_oversampling.to_register();
+        dev.tph_sett.filter = self.filter.to_register();
+        dev.gas_sett.run_gas = if self.gas_enabled {
+            BME680_ENABLE_GAS_MEAS
+        } else {
+            BME680_DISABLE_GAS_MEAS
+        };
+        dev.gas_sett.heatr_ctrl = if self.heater_enabled {
+            BME680_ENABLE_HEATER
+        } else {
+            BME680_DISABLE_HEATER
+        };
+        dev.gas_sett.heatr_temp = self.heater_temperature;
+        dev.gas_sett.heatr_dur = self.heater_duration;
+    }
+}
+
+impl Bme680Config {
+    ///
+    /// Register values for this configuration, with heater set-point `heater_step`
+    /// selected. The heater resistance depends on the sensor's calibration and the
+    /// ambient temperature.
+    ///
+    pub fn to_registers(
+        &self,
+        heater_step: u8,
+        calib: &CalibrationData,
+        amb_temp: i8,
+    ) -> ConfigRegisters {
+        let run_gas = if self.gas_enabled {
+            BME680_ENABLE_GAS_MEAS << BME680_RUN_GAS_POS
+        } else {
+            0
+        };
+        ConfigRegisters {
+            ctrl_gas_0: if self.heater_enabled {
+                BME680_ENABLE_HEATER
+            } else {
+                BME680_DISABLE_HEATER
+            },
+            ctrl_gas_1: run_gas | (heater_step & BME680_NBCONV_MSK),
+            ctrl_hum: self.humidity_oversampling.to_register(),
+            ctrl_meas: (self.temperature_oversampling.to_register() << BME680_OST_POS)
+                | (self.pressure_oversampling.to_register() << BME680_OSP_POS),
+            config: self.filter.to_register() << BME680_FILTER_POS,
+            res_heat: calc_heater_res(self.heater_temperature, amb_temp, calib),
+            gas_wait: calc_heater_dur(self.heater_duration),
+        }
+    }
+
+    ///
+    /// Configuration held by `registers`. The heater temperature is the one that comes
+    /// closest to the programmed heater resistance, so it may be off by a degree. The
+    /// heating duration is what the sensor holds, e.g. 150 ms are stored as 148 ms.
+    ///
+    pub fn from_registers(
+        registers: &ConfigRegisters,
+        calib: &CalibrationData,
+        amb_temp: i8,
+    ) -> Self {
+        let oversampling = |value| Oversampling::from_register(value).unwrap_or(Oversampling::_16X);
+        let res_heat = registers.res_heat as i32;
+        let heater_temperature = (HEATER_TEMPERATURE_MIN..=HEATER_TEMPERATURE_MAX)
+            .min_by_key(|&temperature| {
+                (calc_heater_res(temperature, amb_temp, calib) as i32 - res_heat).abs()
+            })
+            .unwrap_or(HEATER_TEMPERATURE_DEFAULT);
+        let gas_wait = registers.gas_wait;
+        let heater_duration = (gas_wait & 0x3f) as u16 * 4_u16.pow((gas_wait >> 6) as u32);
+        Bme680Config {
+            temperature_oversampling: oversampling(
+                (registers.ctrl_meas & BME680_OST_MSK) >> BME680_OST_POS,
+            ),
+            pressure_oversampling: oversampling(
+                (registers.ctrl_meas & BME680_OSP_MSK) >> BME680_OSP_POS,
+            ),
+            humidity_oversampling: oversampling(registers.ctrl_hum & BME680_OSH_MSK),
+            filter: FilterSize::from((registers.config & BME680_FILTER_MSK) >> BME680_FILTER_POS),
+            gas_enabled: registers.ctrl_gas_1 & BME680_RUN_GAS_MSK != 0,
+            heater_enabled: registers.ctrl_gas_0 & BME680_HCTRL_MSK == BME680_ENABLE_HEATER,
+            heater_temperature,
+            heater_duration,
+        }
+    }
+}
+
+///
+/// Settings held by the sensor's configuration registers
+///
+/// Only the bits holding settings are kept, e.g. `ctrl_meas` without the power mode.
+///
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct ConfigRegisters {
+    /// Heater control, at 0x70
+    pub ctrl_gas_0: u8,
+    /// Gas measurement and selected heater set-point, at 0x71
+    pub ctrl_gas_1: u8,
+    /// Humidity oversampling, at 0x72
+    pub ctrl_hum: u8,
+    /// Temperature and pressure oversampling, at 0x74
+    pub ctrl_meas: u8,
+    /// IIR filter, at 0x75
+    pub config: u8,
+    /// Heater resistance of the selected set-point, at 0x5a and following
+    pub res_heat: u8,
+    /// Heating duration of the selected set-point, at 0x64 and following
+    pub gas_wait: u8,
+}
+
+impl ConfigRegisters {
+    ///
+    /// From the registers 0x70 to 0x75 and the heater registers of the selected set-point.
+    ///
+    pub(crate) fn new(ctrl: &[u8; 6], res_heat: u8, gas_wait: u8) -> Self {
+        ConfigRegisters {
+            ctrl_gas_0: ctrl[0] & BME680_HCTRL_MSK,
+            ctrl_gas_1: ctrl[1] & (BME680_RUN_GAS_MSK | BME680_NBCONV_MSK),
+            ctrl_hum: ctrl[2] & BME680_OSH_MSK,
+            ctrl_meas: ctrl[4] & (BME680_OST_MSK | BME680_OSP_MSK),
+            config: ctrl[5] & BME680_FILTER_MSK,
+            res_heat,
+            gas_wait,
+        }
+    }
+
+    pub fn heater_step(&self) -> u8 {
+        self.ctrl_gas_1 & BME680_NBCONV_MSK
+    }
+
+    ///
+    /// First register that differs from `other`, as address and both values.
+    ///
+    pub fn first_difference(&self, other: &ConfigRegisters) -> Option<(u8, u8, u8)> {
+        let step = self.heater_step();
+        [
+            (
+                BME680_CONF_HEAT_CTRL_ADDR,
+                self.ctrl_gas_0,
+                other.ctrl_gas_0,
+            ),
+            (
+                BME680_CONF_ODR_RUN_GAS_NBC_ADDR,
+                self.ctrl_gas_1,
+                other.ctrl_gas_1,
+            ),
+            (BME680_CONF_OS_H_ADDR, self.ctrl_hum, other.ctrl_hum),
+            (BME680_CONF_T_P_MODE_ADDR, self.ctrl_meas, other.ctrl_meas),
+            (BME680_CONF_ODR_FILT_ADDR, self.config, other.config),
+            (BME680_RES_HEAT0_ADDR + step, self.res_heat, other.res_heat),
+            (BME680_GAS_WAIT0_ADDR + step, self.gas_wait, other.gas_wait),
+        ]
+        .iter()
+        .copied()
+        .find(|(_, own, other)| own != other)
+    }
+}
+
+///
+/// Builds a `Bme680Config`, starting from the default
+///
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Bme680ConfigBuilder {
+    config: Bme680Config,
+}
+
+impl Bme680ConfigBuilder {
+    pub fn temperature_oversampling(mut self, oversampling: Oversampling) -> Self {
+        self.config.temperature_oversampling = oversampling;
+        self
+    }
+
+    pub fn pressure_oversampling(mut self, oversampling: Oversampling) -> Self {
+        self.config.pressure_oversampling = oversampling;
+        self
+    }
+
+    pub fn humidity_oversampling(mut self, oversampling: Oversampling) -> Self {
+        self.config.humidity_oversampling = oversampling;
+        self
+    }
+
+    pub fn filter(mut self, filter: FilterSize) -> Self {
+        self.config.filter = filter;
+        self
+    }
+
+    pub fn gas(mut self, enabled: bool) -> Self {
+        self.config.gas_enabled = enabled;
+        self
+    }
+
+    pub fn heater(mut self, enabled: bool) -> Self {
+        self.config.heater_enabled = enabled;
+        self
+    }
+
+    pub fn heater_temperature(mut self, temperature: u16) -> Self {
+        self.config.heater_temperature = temperature;
+        self
+    }
+
+    pub fn heater_duration(mut self, duration: u16) -> Self {
+        self.config.heater_duration = duration;
+        self
+    }
+
+    pub fn build(self) -> Result<Bme680Config, SensorError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+///
+/// Settings for common use cases, based on Bosch's recommendations
+///
+/// ```
+/// use bme680::config::Preset;
+///
+/// let preset: Preset = "indoor_air_quality".parse().unwrap();
+/// assert_eq!(preset.config().heater_temperature, 320);
+/// assert_eq!(preset.measurement_duration().as_millis(), 193);
+/// ```
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Preset {
+    /// Temperature, pressure and humidity once a minute or less: 1x oversampling, no
+    /// filter and no gas measurement
+    Weather,
+    /// Gas measurements for an air quality estimate: 2x/16x/1x oversampling with the
+    /// heater at 320°C for 150 ms
+    IndoorAirQuality,
+    /// Temperature and humidity only, for battery powered sensors
+    LowPower,
+    /// Pressure at the highest resolution, filtered against short changes like doors
+    /// slamming: 2x/16x oversampling and filter size 15
+    Altimetry,
+}
+
+impl Preset {
+    pub const ALL: [Preset; 4] = [
+        Preset::Weather,
+        Preset::IndoorAirQuality,
+        Preset::LowPower,
+        Preset::Altimetry,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Weather => "weather",
+            Preset::IndoorAirQuality => "indoor_air_quality",
+            Preset::LowPower => "low_power",
+            Preset::Altimetry => "altimetry",
+        }
+    }
+
+    pub fn config(self) -> Bme680Config {
+        let measurements_only = Bme680Config {
+            gas_enabled: false,
+            heater_enabled: false,
+            ..Bme680Config::default()
+        };
+        match self {
+            Preset::Weather => Bme680Config {
+                temperature_oversampling: Oversampling::_1X,
+                pressure_oversampling: Oversampling::_1X,
+                humidity_oversampling: Oversampling::_1X,
+                ..measurements_only
+            },
+            Preset::IndoorAirQuality => Bme680Config {
+                temperature_oversampling: Oversampling::_2X,
+                pressure_oversampling: Oversampling::_16X,
+                humidity_oversampling: Oversampling::_1X,
+                filter: FilterSize::Size0,
+                gas_enabled: true,
+                heater_enabled: true,
+                heater_temperature: 320,
+                heater_duration: 150,
+            },
+            Preset::LowPower => Bme680Config {
+                temperature_oversampling: Oversampling::_1X,
+                humidity_oversampling: Oversampling::_1X,
+                ..measurements_only
+            },
+            Preset::Altimetry => Bme680Config {
+                temperature_oversampling: Oversampling::_2X,
+                pressure_oversampling: Oversampling::_16X,
+                filter: FilterSize::Size15,
+                ..measurements_only
+            },
+        }
+    }
+
+    ///
+    /// See `Bme680Config::measurement_duration`.
+    ///
+    pub fn measurement_duration(self) -> Duration {
+        self.config().measurement_duration()
+    }
+}
+
+impl From<Preset> for Bme680Config {
+    fn from(preset: Preset) -> Self {
+        preset.config()
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Preset {
+    type Err = ConfigError;
+
+    fn from_str(name: &str) -> Result<Self, ConfigError> {
+        Preset::ALL
+            .iter()
+            .copied()
+            .find(|preset| preset.name() == name)
+            .ok_or(ConfigError::Unsupported { setting: "preset" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::fake_device;
+
+    #[test]
+    fn register_values_round_trip() {
+        for oversampling in Oversampling::ALL {
+            assert_eq!(
+                Oversampling::from_register(oversampling.to_register()),
+                Some(oversampling)
+            );
+            assert_eq!(
+                Oversampling::from_factor(oversampling.factor()),
+                Some(oversampling)
+            );
+        }
+        assert_eq!(Oversampling::from_factor(3), None);
+        for value in 0..=7 {
+            let filter = FilterSize::from_register(value).unwrap();
+            assert_eq!(filter.to_register(), value);
+        }
+        assert_eq!(FilterSize::from_register(8), None);
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let mut sensor = fake_device(0);
+        for oversampling in Oversampling::ALL {
+            let config = Bme680Config::builder()
+                .temperature_oversampling(oversampling)
+                .pressure_oversampling(oversampling)
+                .filter(FilterSize::Size63)
+                .gas(true)
+                .heater(false)
+                .heater_temperature(250)
+                .heater_duration(80)
+                .build()
+                .unwrap();
+            sensor.apply_config(&config).unwrap();
+            assert_eq!(sensor.config(), config);
+            assert_eq!(sensor.get_temperature_oversampling(), oversampling);
+        }
+    }
+
+    #[test]
+    fn presets() {
+        for preset in Preset::ALL {
+            assert!(preset.config().validate().is_ok());
+            assert_eq!(preset.to_string().parse(), Ok(preset));
+        }
+        assert_eq!(
+            "outdoor".parse::<Preset>(),
+            Err(ConfigError::Unsupported { setting: "preset" })
+        );
+        assert_eq!(Preset::Weather.measurement_duration().as_millis(), 11);
+        assert!(Preset::LowPower.measurement_duration() < Preset::Weather.measurement_duration());
+        assert_eq!(
+            Bme680Config::from(Preset::IndoorAirQuality).heater_duration,
+            150
+        );
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        assert!(matches!(
+            Bme680Config::builder().heater_temperature(500).build(),
+            Err(SensorError::InvalidConfiguration(ConfigError::OutOfRange {
+                setting: "heater_temperature",
+                ..
+            }))
+        ));
+        assert!(matches!(
+            Bme680Config::builder()
+                .humidity_oversampling(Oversampling::_1X)
+                .build(),
+            Err(SensorError::InvalidConfiguration(ConfigError::Requires {
+                setting: "humidity_oversampling",
+                ..
+            }))
+        ));
+
+        let mut sensor = fake_device(0);
+        let invalid = Bme680Config {
+            heater_duration: 0,
+            ..Bme680Config::default()
+        };
+        assert!(sensor.apply_config(&invalid).is_err());
+        assert_eq!(sensor.config(), Bme680Config::default());
+    }
+}
@@ -0,0 +1,650 @@
+//!
+//! Settings and measurement steps shared by `BME680` and `AsyncBME680`
+//!
+//! Both sensors keep their settings in a `Controller` and run its steps against their bus.
+//! The futures of blocking buses are ready right away, `BME680` runs them with `block_on`.
+//!
+
+use crate::compensation::{self, CalibrationData};
+use crate::config::{Bme680Config, ConfigRegisters};
+use crate::errors::{BusError, ConfigError, SensorError};
+use crate::iaq::{Iaq, IaqEstimator};
+use crate::source::*;
+use crate::{
+    driver, Bme680Data, GasScan, GasScanReading, HeaterStep, UnstableHeaterPolicy,
+    HEATER_DURATION_DEFAULT, HEATER_PROFILE_MAX_STEPS, HEATER_TEMPERATURE_DEFAULT,
+};
+
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use log::{debug, trace};
+
+/// Waker of futures that never wait, so waking them does nothing
+const NOOP_WAKER: RawWaker = {
+    const VTABLE: RawWakerVTable = RawWakerVTable::new(|_| NOOP_WAKER, |_| {}, |_| {}, |_| {});
+    RawWaker::new(core::ptr::null(), &VTABLE)
+};
+
+///
+/// Runs a future that never waits, like the ones of blocking buses.
+///
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    // SAFETY: the vtable's functions do nothing, so they uphold the `RawWaker` contract
+    let waker = unsafe { Waker::from_raw(NOOP_WAKER) };
+    let mut future = pin!(future);
+    match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+        Poll::Ready(output) => output,
+        Poll::Pending => unreachable!("blocking buses never wait"),
+    }
+}
+
+///
+/// Driver calls on the bus of a sensor, see the driver backends for what they do
+///
+pub(crate) trait Access {
+    async fn init(&mut self, dev: &mut bme680_dev) -> i8;
+    async fn set_sensor_settings(&mut self, desired_settings: u16, dev: &mut bme680_dev) -> i8;
+    async fn set_sensor_mode(&mut self, dev: &mut bme680_dev) -> i8;
+    async fn get_sensor_data(&mut self, data: &mut bme680_field_data, dev: &mut bme680_dev) -> i8;
+    async fn read_regs(&mut self, reg_addr: u8, reg_data: &mut [u8], dev: &mut bme680_dev) -> i8;
+    async fn write_regs(&mut self, reg_addr: &[u8], reg_data: &[u8], dev: &mut bme680_dev) -> i8;
+    async fn delay_ms(&mut self, period: u32);
+
+    /// Last failed access, as the driver backends only report a result code
+    fn take_error(&mut self) -> Option<BusError>;
+}
+
+///
+/// Error for a driver result, with the failed bus access if there was one.
+///
+fn error<A: Access>(access: &mut A, rslt: i8) -> SensorError {
+    match access.take_error() {
+        Some(e) if rslt == BME680_E_COM_FAIL => SensorError::CommunicationError(e),
+        _ => SensorError::from(rslt),
+    }
+}
+
+fn result<A: Access>(access: &mut A, rslt: i8) -> Result<(), SensorError> {
+    if rslt == BME680_OK {
+        Ok(())
+    } else {
+        Err(error(access, rslt))
+    }
+}
+
+pub(crate) struct Controller {
+    pub(crate) native_device: bme680_dev,
+    pub(crate) reset: bool,
+    pub(crate) measure_period: u16,
+    settings: u16,
+    pub(crate) heater_profile: [HeaterStep; HEATER_PROFILE_MAX_STEPS],
+    pub(crate) heater_profile_len: usize,
+    pub(crate) iaq: IaqEstimator,
+    pub(crate) heater_policy: UnstableHeaterPolicy,
+    pub(crate) verify_writes: bool,
+}
+
+impl Controller {
+    pub(crate) fn new(mut dev: bme680_dev) -> Self {
+        dev.gas_sett.heatr_temp = HEATER_TEMPERATURE_DEFAULT;
+        dev.gas_sett.heatr_dur = HEATER_DURATION_DEFAULT;
+        Controller {
+            native_device: dev,
+            measure_period: 20, // some value, will be changed on first read
+            reset: true,
+            settings: BME680_OST_SEL
+                | BME680_OSP_SEL
+                | BME680_OSH_SEL
+                | BME680_FILTER_SEL
+                | BME680_HCNTRL_SEL
+                | BME680_GAS_SENSOR_SEL,
+            heater_profile: [HeaterStep::default(); HEATER_PROFILE_MAX_STEPS],
+            heater_profile_len: 0,
+            iaq: IaqEstimator::default(),
+            heater_policy: UnstableHeaterPolicy::default(),
+            verify_writes: false,
+        }
+    }
+
+    pub(crate) fn calibration(&self) -> CalibrationData {
+        driver::calibration(&self.native_device)
+    }
+
+    pub(crate) fn config(&self) -> Bme680Config {
+        Bme680Config::from_device(&self.native_device)
+    }
+
+    pub(crate) fn gas_enabled(&self) -> bool {
+        self.native_device.gas_sett.run_gas == BME680_ENABLE_GAS_MEAS
+    }
+
+    pub(crate) fn heater_enabled(&self) -> bool {
+        self.native_device.gas_sett.heatr_ctrl == BME680_ENABLE_HEATER
+    }
+
+    pub(crate) async fn init<A: Access>(&mut self, access: &mut A) -> Result<(), SensorError> {
+        let dev_id = self.native_device.dev_id;
+        let init_result = access.init(&mut self.native_device).await;
+        if init_result != BME680_OK {
+            debug!("failed to initialize sensor 0x{:x}", dev_id);
+            Err(error(access, init_result))
+        } else {
+            debug!("successfully initialized sensor 0x{:x}", dev_id);
+            Ok(())
+        }
+    }
+
+    async fn activate_device<A: Access>(&mut self, access: &mut A) -> Result<(), SensorError> {
+        let mut rslt;
+        self.native_device.power_mode = BME680_FORCED_MODE;
+        let mut retries = 10;
+        loop {
+            rslt = access.set_sensor_mode(&mut self.native_device).await;
+            if retries == 0 || rslt == BME680_OK {
+                break;
+            }
+            retries -= 1;
+            debug!("Retrying setting the sensor to forced: {} left", retries);
+        }
+        if rslt == BME680_OK {
+            trace!("sensor set to FORCED");
+            Ok(())
+        } else {
+            let e = error(access, rslt);
+            trace!("error setting sensor to forced: '{}'", e);
+            Err(e)
+        }
+    }
+
+    async fn read_prep<A: Access>(&mut self, access: &mut A) -> Result<(), SensorError> {
+        // the heater can only be configured for forced mode
+        self.native_device.power_mode = BME680_FORCED_MODE;
+        let rslt = access
+            .set_sensor_settings(self.settings, &mut self.native_device)
+            .await;
+
+        if rslt == BME680_OK && self.verify_writes {
+            self.verify_config(access).await?;
+        }
+        self.activate_device(access).await?;
+
+        self.measure_period = driver::get_profile_dur(&self.native_device);
+        if rslt == BME680_OK {
+            trace!("sensor prepared");
+            self.reset = false;
+            Ok(())
+        } else {
+            let e = error(access, rslt);
+            trace!("sensor preparation error: '{}'", e);
+            Err(e)
+        }
+    }
+
+    pub(crate) async fn read_all<A: Access>(
+        &mut self,
+        access: &mut A,
+    ) -> Result<Bme680Data, SensorError> {
+        let mut data = self.measure(access).await?;
+        for _ in 0..self.heater_policy.retries() {
+            if !self.heater_policy.needs_retry(&data) {
+                break;
+            }
+            debug!("heater not stable, measuring again");
+            data = self.measure(access).await?;
+        }
+        Ok(self.heater_policy.apply(data))
+    }
+
+    async fn measure<A: Access>(&mut self, access: &mut A) -> Result<Bme680Data, SensorError> {
+        let mut data = bme680_field_data::default();
+        if self.reset {
+            self.read_prep(access).await?;
+        } else {
+            self.activate_device(access).await?;
+        }
+        access.delay_ms(self.measure_period as u32).await;
+        let rslt = access
+            .get_sensor_data(&mut data, &mut self.native_device)
+            .await;
+        if rslt == BME680_OK {
+            Ok(Bme680Data::from_field_data(&data, self.config()))
+        } else {
+            let e = error(access, rslt);
+            trace!("error reading data: '{}'", e);
+            Err(e)
+        }
+    }
+
+    pub(crate) async fn read_iaq<A: Access>(&mut self, access: &mut A) -> Result<Iaq, SensorError> {
+        if !self.gas_enabled() {
+            return Err(ConfigError::Requires {
+                setting: "read_iaq",
+                requirement: "gas measurements",
+            }
+            .into());
+        }
+        let data = self.read_all(access).await?;
+        Ok(match data.gas_resistance {
+            Some(gas_resistance) => self.iaq.update(gas_resistance, data.humidity),
+            None => self.iaq.last(),
+        })
+    }
+
+    pub(crate) async fn read_config<A: Access>(
+        &mut self,
+        access: &mut A,
+    ) -> Result<Bme680Config, SensorError> {
+        let registers = self.read_config_registers(access).await?;
+        Ok(Bme680Config::from_registers(
+            &registers,
+            &self.calibration(),
+            self.native_device.amb_temp,
+        ))
+    }
+
+    pub(crate) async fn read_config_registers<A: Access>(
+        &mut self,
+        access: &mut A,
+    ) -> Result<ConfigRegisters, SensorError> {
+        let mut ctrl = [0_u8; 6];
+        self.read_regs(access, BME680_CONF_HEAT_CTRL_ADDR, &mut ctrl)
+            .await?;
+        let step = ctrl[1] & BME680_NBCONV_MSK;
+        let mut res_heat = [0_u8];
+        self.read_regs(access, BME680_RES_HEAT0_ADDR + step, &mut res_heat)
+            .await?;
+        let mut gas_wait = [0_u8];
+        self.read_regs(access, BME680_GAS_WAIT0_ADDR + step, &mut gas_wait)
+            .await?;
+        Ok(ConfigRegisters::new(&ctrl, res_heat[0], gas_wait[0]))
+    }
+
+    async fn read_regs<A: Access>(
+        &mut self,
+        access: &mut A,
+        reg_addr: u8,
+        data: &mut [u8],
+    ) -> Result<(), SensorError> {
+        let rslt = access
+            .read_regs(reg_addr, data, &mut self.native_device)
+            .await;
+        result(access, rslt)
+    }
+
+    async fn verify_config<A: Access>(&mut self, access: &mut A) -> Result<(), SensorError> {
+        let expected = self.config().to_registers(
+            self.native_device.gas_sett.nb_conv,
+            &self.calibration(),
+            self.native_device.amb_temp,
+        );
+        let actual = self.read_config_registers(access).await?;
+        match expected.first_difference(&actual) {
+            Some((register, expected, actual)) => {
+                debug!(
+                    "settings not written, 0x{:02x} holds 0x{:02x}",
+                    register, actual
+                );
+                Err(SensorError::VerificationFailed {
+                    register,
+                    expected,
+                    actual,
+                })
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn write_heater_profile<A: Access>(&mut self, access: &mut A) -> Result<(), SensorError> {
+        let calib = self.calibration();
+        let len = self.heater_profile_len;
+        let mut res_heat_addr = [0_u8; HEATER_PROFILE_MAX_STEPS];
+        let mut res_heat = [0_u8; HEATER_PROFILE_MAX_STEPS];
+        let mut gas_wait_addr = [0_u8; HEATER_PROFILE_MAX_STEPS];
+        let mut gas_wait = [0_u8; HEATER_PROFILE_MAX_STEPS];
+        for (i, step) in self.heater_profile[..len].iter().enumerate() {
+            res_heat_addr[i] = BME680_RES_HEAT0_ADDR + i as u8;
+            res_heat[i] = compensation::calc_heater_res(
+                step.temperature,
+                self.native_device.amb_temp,
+                &calib,
+            );
+            gas_wait_addr[i] = BME680_GAS_WAIT0_ADDR + i as u8;
+            gas_wait[i] = compensation::calc_heater_dur(step.duration);
+        }
+        for (addr, data) in [(&res_heat_addr, &res_heat), (&gas_wait_addr, &gas_wait)] {
+            let rslt = access
+                .write_regs(&addr[..len], &data[..len], &mut self.native_device)
+                .await;
+            result(access, rslt)?;
+        }
+        Ok(())
+    }
+
+    async fn select_heater_step<A: Access>(
+        &mut self,
+        access: &mut A,
+        index: u8,
+    ) -> Result<(), SensorError> {
+        self.native_device.gas_sett.nb_conv = index;
+        let rslt = access
+            .set_sensor_settings(
+                BME680_NBCONV_SEL | BME680_RUN_GAS_SEL,
+                &mut self.native_device,
+            )
+            .await;
+        result(access, rslt)
+    }
+
+    pub(crate) async fn scan_heater_profile<A: Access>(
+        &mut self,
+        access: &mut A,
+    ) -> Result<GasScan, SensorError> {
+        let requirement = if self.heater_profile_len == 0 {
+            Some("a heater profile")
+        } else if !self.gas_enabled() {
+            Some("gas measurements")
+        } else if !self.heater_enabled() {
+            Some("the heater")
+        } else {
+            None
+        };
+        if let Some(requirement) = requirement {
+            return Err(ConfigError::Requires {
+                setting: "scan_heater_profile",
+                requirement,
+            }
+            .into());
+        }
+        if self.reset {
+            self.read_prep(access).await?;
+        }
+        // whatever happens from here, the single heater set-point has to be restored
+        self.reset = true;
+        self.write_heater_profile(access).await?;
+
+        let mut scan = GasScan::default();
+        let tph_period = self
+            .measure_period
+            .saturating_sub(self.native_device.gas_sett.heatr_dur);
+        for i in 0..self.heater_profile_len {
+            let step = self.heater_profile[i];
+            self.select_heater_step(access, i as u8).await?;
+            self.activate_device(access).await?;
+            access.delay_ms((tph_period + step.duration) as u32).await;
+
+            let mut data = bme680_field_data::default();
+            let rslt = access
+                .get_sensor_data(&mut data, &mut self.native_device)
+                .await;
+            if rslt != BME680_OK {
+                let e = error(access, rslt);
+                trace!("error reading heater step {}: '{}'", i, e);
+                return Err(e);
+            }
+            scan.readings[i] = GasScanReading {
+                gas_index: data.gas_index,
+                step,
+                gas_resistance: if data.status & BME680_GASM_VALID_MSK != 0 {
+                    Some(data.gas_resistance)
+                } else {
+                    None
+                },
+            };
+            scan.len += 1;
+        }
+        Ok(scan)
+    }
+}
+
+///
+/// Methods of `BME680` and `AsyncBME680` that only change settings, with the ones that
+/// access the bus the sensors run the `Controller` steps themselves.
+///
+macro_rules! settings_api {
+    () => {
+        ///
+        /// Calibration data read from the sensor during initialization, e.g. for compensating
+        /// raw readings offline.
+        ///
+        pub fn calibration(&self) -> $crate::compensation::CalibrationData {
+            self.controller.calibration()
+        }
+
+        ///
+        /// The estimator behind `read_iaq`.
+        ///
+        pub fn iaq_estimator(&self) -> &$crate::iaq::IaqEstimator {
+            &self.controller.iaq
+        }
+
+        ///
+        /// Replaces the estimator behind `read_iaq`, e.g. to change its burn-in or baseline window.
+        ///
+        pub fn set_iaq_estimator(&mut self, estimator: $crate::iaq::IaqEstimator) {
+            self.controller.iaq = estimator;
+        }
+
+        pub fn get_unstable_heater_policy(&self) -> $crate::UnstableHeaterPolicy {
+            self.controller.heater_policy
+        }
+
+        pub fn set_unstable_heater_policy(&mut self, policy: $crate::UnstableHeaterPolicy) {
+            self.controller.heater_policy = policy;
+        }
+
+        ///
+        /// Settings the next measurements are taken with.
+        ///
+        pub fn config(&self) -> $crate::config::Bme680Config {
+            self.controller.config()
+        }
+
+        ///
+        /// Replaces all settings with `config` after validating it. Like the individual
+        /// setters, the sensor is configured with the next measurement.
+        ///
+        pub fn apply_config(
+            &mut self,
+            config: &$crate::config::Bme680Config,
+        ) -> Result<(), $crate::errors::SensorError> {
+            config.validate()?;
+            config.write_to(&mut self.controller.native_device);
+            self.controller.reset = true;
+            Ok(())
+        }
+
+        ///
+        /// If enabled, the settings are read back after writing them, and measurements fail
+        /// with `VerificationFailed` if the sensor holds something else.
+        ///
+        pub fn set_verify_writes(&mut self, verify: bool) {
+            self.controller.verify_writes = verify;
+            self.controller.reset = true;
+        }
+
+        pub fn get_verify_writes(&self) -> bool {
+            self.controller.verify_writes
+        }
+
+        pub fn get_pressure_oversampling(&self) -> $crate::Oversampling {
+            self.config().pressure_oversampling
+        }
+
+        pub fn get_humidity_oversampling(&self) -> $crate::Oversampling {
+            self.config().humidity_oversampling
+        }
+
+        pub fn get_temperature_oversampling(&self) -> $crate::Oversampling {
+            self.config().temperature_oversampling
+        }
+
+        ///
+        /// Pressure and humidity are compensated with the temperature, so they can only be
+        /// measured while temperature oversampling is enabled, as checked by
+        /// `Bme680Config::validate`.
+        ///
+        pub fn set_pressure_oversampling(
+            &mut self,
+            oversampling: $crate::Oversampling,
+        ) -> Result<(), $crate::errors::SensorError> {
+            let mut config = self.config();
+            config.pressure_oversampling = oversampling;
+            config.check_oversampling()?;
+            self.controller.native_device.tph_sett.os_pres = oversampling.to_register();
+            self.controller.reset = true;
+            Ok(())
+        }
+
+        ///
+        /// Like `set_pressure_oversampling`, needs temperature oversampling to be enabled.
+        ///
+        pub fn set_humidity_oversampling(
+            &mut self,
+            oversampling: $crate::Oversampling,
+        ) -> Result<(), $crate::errors::SensorError> {
+            let mut config = self.config();
+            config.humidity_oversampling = oversampling;
+            config.check_oversampling()?;
+            self.controller.native_device.tph_sett.os_hum = oversampling.to_register();
+            self.controller.reset = true;
+            Ok(())
+        }
+
+        ///
+        /// Fails with `InvalidConfiguration` when disabling the temperature while pressure or
+        /// humidity are measured.
+        ///
+        pub fn set_temperature_oversampling(
+            &mut self,
+            oversampling: $crate::Oversampling,
+        ) -> Result<(), $crate::errors::SensorError> {
+            let mut config = self.config();
+            config.temperature_oversampling = oversampling;
+            config.check_oversampling()?;
+            self.controller.native_device.tph_sett.os_temp = oversampling.to_register();
+            self.controller.reset = true;
+            Ok(())
+        }
+
+        pub fn get_filter_size(&self) -> $crate::FilterSize {
+            self.config().filter
+        }
+
+        pub fn set_filter_size(&mut self, filter: $crate::FilterSize) {
+            self.controller.native_device.tph_sett.filter = filter.to_register();
+            self.controller.reset = true;
+        }
+
+        ///
+        /// Enables or disables the gas measurement. Without it, measurements are done
+        /// as soon as temperature, pressure and humidity are available.
+        ///
+        pub fn set_enable_gas_resistence(&mut self, enable: bool) {
+            self.controller.native_device.gas_sett.run_gas = if enable {
+                $crate::source::BME680_ENABLE_GAS_MEAS
+            } else {
+                $crate::source::BME680_DISABLE_GAS_MEAS
+            };
+            self.controller.reset = true;
+        }
+
+        pub fn get_gas_resistence(&self) -> bool {
+            self.controller.gas_enabled()
+        }
+
+        ///
+        /// Switches the gas sensor's heater on or off. With the heater off, gas
+        /// measurements are not meaningful, but the sensor doesn't heat up its surroundings.
+        ///
+        pub fn set_enable_heater(&mut self, enable: bool) {
+            self.controller.native_device.gas_sett.heatr_ctrl = if enable {
+                $crate::source::BME680_ENABLE_HEATER
+            } else {
+                $crate::source::BME680_DISABLE_HEATER
+            };
+            self.controller.reset = true;
+        }
+
+        pub fn get_heater_enabled(&self) -> bool {
+            self.controller.heater_enabled()
+        }
+
+        ///
+        /// Target temperature of the gas sensor's heater in °C.
+        ///
+        pub fn get_heater_temperature(&self) -> u16 {
+            self.controller.native_device.gas_sett.heatr_temp
+        }
+
+        ///
+        /// Sets the heater's target temperature, between `HEATER_TEMPERATURE_MIN` and
+        /// `HEATER_TEMPERATURE_MAX` °C.
+        ///
+        pub fn set_heater_temperature(
+            &mut self,
+            temperature: u16,
+        ) -> Result<(), $crate::errors::SensorError> {
+            $crate::check_heater_step(
+                temperature,
+                self.controller.native_device.gas_sett.heatr_dur,
+            )?;
+            self.controller.native_device.gas_sett.heatr_temp = temperature;
+            self.controller.reset = true;
+            Ok(())
+        }
+
+        ///
+        /// Time the heater is given to reach its target temperature in ms.
+        ///
+        pub fn get_heater_duration(&self) -> u16 {
+            self.controller.native_device.gas_sett.heatr_dur
+        }
+
+        ///
+        /// Sets the heating duration, between 1 and `HEATER_DURATION_MAX` ms. Longer durations
+        /// are stored with less precision by the sensor (down to steps of 64 ms).
+        ///
+        pub fn set_heater_duration(
+            &mut self,
+            duration: u16,
+        ) -> Result<(), $crate::errors::SensorError> {
+            $crate::check_heater_step(self.controller.native_device.gas_sett.heatr_temp, duration)?;
+            self.controller.native_device.gas_sett.heatr_dur = duration;
+            self.controller.reset = true;
+            Ok(())
+        }
+
+        ///
+        /// Heater profile used by `scan_heater_profile`.
+        ///
+        pub fn get_heater_profile(&self) -> &[$crate::HeaterStep] {
+            &self.controller.heater_profile[..self.controller.heater_profile_len]
+        }
+
+        ///
+        /// Sets the heater profile for `scan_heater_profile`, up to `HEATER_PROFILE_MAX_STEPS`
+        /// steps. Each step has to be within the limits of `set_heater_temperature` and
+        /// `set_heater_duration`.
+        ///
+        pub fn set_heater_profile(
+            &mut self,
+            steps: &[$crate::HeaterStep],
+        ) -> Result<(), $crate::errors::SensorError> {
+            $crate::check_range(
+                "heater_profile",
+                steps.len() as u32,
+                1,
+                $crate::HEATER_PROFILE_MAX_STEPS as u32,
+            )?;
+            for step in steps {
+                $crate::check_heater_step(step.temperature, step.duration)?;
+            }
+            self.controller.heater_profile[..steps.len()].copy_from_slice(steps);
+            self.controller.heater_profile_len = steps.len();
+            Ok(())
+        }
+    };
+}
+
+pub(crate) use settings_api;
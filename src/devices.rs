@@ -10,6 +10,9 @@ pub trait Barometer {
 }
 
 pub trait AirQualitySensor {
+    ///
+    /// Measures and returns an air quality index between 0 (excellent) and 500.
+    ///
     fn aqi(&mut self) -> Result<f32, SensorError>;
     fn gas_resistance(&mut self) -> Result<Option<u32>, SensorError>;
 }
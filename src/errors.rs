@@ -1,45 +1,377 @@
 use crate::source::{
     BME680_E_COM_FAIL, BME680_E_DEV_NOT_FOUND, BME680_E_INVALID_LENGTH, BME680_E_NULL_PTR,
+    BME680_W_DEFINE_PWR_MODE, BME680_W_NO_NEW_DATA,
 };
 
-#[derive(Copy, Clone, Debug)]
+use core::fmt::{self, Display};
+#[cfg(feature = "std")]
+use std::sync::Arc;
+
+#[derive(Clone, Debug)]
 pub enum SensorError {
-    CommunicationError = BME680_E_COM_FAIL as isize,
-    DeviceNotFound = BME680_E_DEV_NOT_FOUND as isize,
-    InvalidLength = BME680_E_INVALID_LENGTH as isize,
-    NullPointer = BME680_E_NULL_PTR as isize,
-    Unknown,
+    /// Accessing the sensor's registers failed
+    CommunicationError(BusError),
+    DeviceNotFound,
+    InvalidLength,
+    NullPointer,
+    /// The sensor was asked for a power mode the driver doesn't know
+    PowerModeNotDefined,
+    /// The sensor finished without reporting new data
+    NoNewData,
+    InvalidConfiguration(ConfigError),
+    /// Reading back a setting returned something other than what was written
+    VerificationFailed {
+        register: u8,
+        expected: u8,
+        actual: u8,
+    },
+    /// A driver result without a known meaning
+    Unknown(i8),
 }
 
-impl std::error::Error for SensorError {}
+impl SensorError {
+    ///
+    /// Result code of Bosch's driver this error corresponds to, if any.
+    ///
+    pub fn code(&self) -> Option<i8> {
+        match self {
+            SensorError::CommunicationError(_) => Some(BME680_E_COM_FAIL),
+            SensorError::DeviceNotFound => Some(BME680_E_DEV_NOT_FOUND),
+            SensorError::InvalidLength => Some(BME680_E_INVALID_LENGTH),
+            SensorError::NullPointer => Some(BME680_E_NULL_PTR),
+            SensorError::PowerModeNotDefined => Some(BME680_W_DEFINE_PWR_MODE),
+            SensorError::NoNewData => Some(BME680_W_NO_NEW_DATA),
+            SensorError::InvalidConfiguration(_) => None,
+            SensorError::VerificationFailed { .. } => None,
+            SensorError::Unknown(code) => Some(*code),
+        }
+    }
 
-impl std::fmt::Display for SensorError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let msg = match self {
-            SensorError::CommunicationError => {
-                format!("Communication Error, code '{}'", *self as u8)
-            }
-            SensorError::DeviceNotFound => format!("Device not found, code '{}'", *self as u8),
-            SensorError::InvalidLength => format!("Invalid length, code '{}'", *self as u8),
-            SensorError::NullPointer => {
-                format!("Internal Null Pointer encountered, code '{}'", *self as u8)
-            }
-            SensorError::Unknown => format!("An unknown error occurred, code '{}'", *self as u8),
-        };
-        write!(f, "{}", &msg)
+    ///
+    /// Whether Bosch's driver reports this as a warning rather than an error, i.e. the
+    /// sensor works but the call had no effect.
+    ///
+    pub fn is_warning(&self) -> bool {
+        matches!(
+            self,
+            SensorError::PowerModeNotDefined | SensorError::NoNewData
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SensorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SensorError::CommunicationError(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl Display for SensorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SensorError::CommunicationError(e) => write!(f, "Communication Error, {}", e)?,
+            SensorError::DeviceNotFound => write!(f, "Device not found")?,
+            SensorError::InvalidLength => write!(f, "Invalid length")?,
+            SensorError::NullPointer => write!(f, "Internal Null Pointer encountered")?,
+            SensorError::PowerModeNotDefined => write!(f, "Power mode not defined")?,
+            SensorError::NoNewData => write!(f, "No new data")?,
+            SensorError::InvalidConfiguration(e) => write!(f, "Invalid configuration, {}", e)?,
+            SensorError::VerificationFailed {
+                register,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Register 0x{:02x} holds 0x{:02x} instead of 0x{:02x}",
+                register, actual, expected
+            )?,
+            SensorError::Unknown(_) => write!(f, "An unknown error occurred")?,
+        }
+        match self.code() {
+            Some(code) => write!(f, ", code '{}'", code),
+            None => Ok(()),
+        }
     }
 }
 
 impl From<i8> for SensorError {
     fn from(error: i8) -> Self {
         match error {
-            error if error == SensorError::CommunicationError as i8 => {
-                SensorError::CommunicationError
+            BME680_E_COM_FAIL => SensorError::CommunicationError(BusError::default()),
+            BME680_E_DEV_NOT_FOUND => SensorError::DeviceNotFound,
+            BME680_E_INVALID_LENGTH => SensorError::InvalidLength,
+            BME680_E_NULL_PTR => SensorError::NullPointer,
+            BME680_W_DEFINE_PWR_MODE => SensorError::PowerModeNotDefined,
+            BME680_W_NO_NEW_DATA => SensorError::NoNewData,
+            code => SensorError::Unknown(code),
+        }
+    }
+}
+
+impl From<BusError> for SensorError {
+    fn from(error: BusError) -> Self {
+        SensorError::CommunicationError(error)
+    }
+}
+
+impl From<ConfigError> for SensorError {
+    fn from(error: ConfigError) -> Self {
+        SensorError::InvalidConfiguration(error)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BusOperation {
+    Read,
+    Write,
+}
+
+///
+/// Error of a bus the sensor is attached to
+///
+/// Bus errors only need to be `Debug`, like the errors of most HALs. With the `std` feature
+/// they are passed on as the `source()` of a `BusError`, see there.
+///
+pub trait BusCause: fmt::Debug + 'static {}
+
+impl<E: fmt::Debug + 'static> BusCause for E {}
+
+///
+/// A failed access to the sensor's registers
+///
+/// With the `std` feature, the error of the bus is kept as `source()`. Errors of the Linux
+/// buses, `std::io::Error` and errors of replayed traces keep their type, other errors
+/// are passed on as a `DebugCause` with their debug output.
+///
+#[derive(Clone, Debug, Default)]
+pub struct BusError {
+    access: Option<(BusOperation, u8)>,
+    #[cfg(feature = "std")]
+    cause: Option<Arc<dyn std::error::Error + Send + Sync>>,
+}
+
+impl BusError {
+    pub(crate) fn new<E: BusCause>(error: E) -> Self {
+        #[cfg(not(feature = "std"))]
+        let _ = error;
+        BusError {
+            access: None,
+            #[cfg(feature = "std")]
+            cause: Some(cause(error)),
+        }
+    }
+
+    pub(crate) fn at(mut self, operation: BusOperation, register: u8) -> Self {
+        self.access = Some((operation, register));
+        self
+    }
+
+    pub fn operation(&self) -> Option<BusOperation> {
+        self.access.map(|(operation, _)| operation)
+    }
+
+    ///
+    /// The register the failed access started at.
+    ///
+    pub fn register(&self) -> Option<u8> {
+        self.access.map(|(_, register)| register)
+    }
+
+    ///
+    /// Error of the bus, if known.
+    ///
+    #[cfg(feature = "std")]
+    pub fn cause(&self) -> Option<&(dyn std::error::Error + Send + Sync + 'static)> {
+        self.cause.as_deref()
+    }
+}
+
+impl Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.access {
+            Some((BusOperation::Read, register)) => {
+                write!(f, "failed to read register 0x{:02x}", register)
             }
-            error if error == SensorError::DeviceNotFound as i8 => SensorError::DeviceNotFound,
-            error if error == SensorError::InvalidLength as i8 => SensorError::InvalidLength,
-            error if error == SensorError::NullPointer as i8 => SensorError::NullPointer,
-            _ => SensorError::Unknown,
+            Some((BusOperation::Write, register)) => {
+                write!(f, "failed to write register 0x{:02x}", register)
+            }
+            None => write!(f, "bus access failed"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BusError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.cause
+            .as_deref()
+            .map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+
+///
+/// Error of a bus that only provides its debug output
+///
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+pub struct DebugCause(pub String);
+
+#[cfg(feature = "std")]
+impl Display for DebugCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DebugCause {}
+
+///
+/// Keeps the bus errors we know the type of, see `BusError`
+///
+#[cfg(feature = "std")]
+fn cause<E: BusCause>(error: E) -> Arc<dyn std::error::Error + Send + Sync> {
+    use std::any::Any;
+
+    fn keep<T: std::error::Error + Send + Sync + 'static>(
+        error: Box<dyn Any>,
+    ) -> Result<Arc<dyn std::error::Error + Send + Sync>, Box<dyn Any>> {
+        error
+            .downcast::<T>()
+            .map(|e| Arc::new(*e) as Arc<dyn std::error::Error + Send + Sync>)
+    }
+
+    let description = format!("{:?}", error);
+    let error: Box<dyn Any> = Box::new(error);
+    #[cfg(feature = "linux")]
+    let error = match keep::<linux_embedded_hal::i2cdev::linux::LinuxI2CError>(error)
+        .or_else(keep::<crate::interface::SpiError<std::io::Error, core::convert::Infallible>>)
+    {
+        Ok(cause) => return cause,
+        Err(error) => error,
+    };
+    #[cfg(any(test, feature = "testing"))]
+    let error = match keep::<crate::testing::SimulatorError>(error) {
+        Ok(cause) => return cause,
+        Err(error) => error,
+    };
+    keep::<std::io::Error>(error)
+        .or_else(keep::<crate::trace::ReplayError>)
+        .unwrap_or_else(|_| Arc::new(DebugCause(description)))
+}
+
+///
+/// A setting the sensor can't be configured with
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ConfigError {
+    /// `value` is outside of `min..=max`
+    OutOfRange {
+        setting: &'static str,
+        value: u32,
+        min: u32,
+        max: u32,
+    },
+    /// `setting` only works with `requirement`
+    Requires {
+        setting: &'static str,
+        requirement: &'static str,
+    },
+    /// The value of `setting` is taken already
+    Duplicate { setting: &'static str },
+    /// The value of `setting` isn't supported
+    Unsupported { setting: &'static str },
+}
+
+impl ConfigError {
+    ///
+    /// Name of the setting that can't be used.
+    ///
+    pub fn setting(&self) -> &'static str {
+        match self {
+            ConfigError::OutOfRange { setting, .. }
+            | ConfigError::Requires { setting, .. }
+            | ConfigError::Duplicate { setting }
+            | ConfigError::Unsupported { setting } => setting,
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::OutOfRange {
+                setting,
+                value,
+                min,
+                max,
+            } => write!(
+                f,
+                "{} is {}, but has to be within {}..={}",
+                setting, value, min, max
+            ),
+            ConfigError::Requires {
+                setting,
+                requirement,
+            } => write!(f, "{} requires {}", setting, requirement),
+            ConfigError::Duplicate { setting } => write!(f, "{} is taken already", setting),
+            ConfigError::Unsupported { setting } => write!(f, "{} is not supported", setting),
         }
     }
 }
+
+#[cfg(feature = "std")]
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn driver_codes() {
+        assert!(matches!(
+            SensorError::from(BME680_W_NO_NEW_DATA),
+            SensorError::NoNewData
+        ));
+        assert!(SensorError::from(BME680_W_DEFINE_PWR_MODE).is_warning());
+        assert!(matches!(SensorError::from(-42), SensorError::Unknown(-42)));
+        for code in [
+            BME680_E_COM_FAIL,
+            BME680_E_NULL_PTR,
+            BME680_W_NO_NEW_DATA,
+            7,
+        ] {
+            assert_eq!(SensorError::from(code).code(), Some(code));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn cause_is_kept() {
+        use std::error::Error;
+
+        let io = std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out");
+        let e = SensorError::from(BusError::new(io).at(BusOperation::Read, 0x1d));
+        assert_eq!(
+            e.to_string(),
+            "Communication Error, failed to read register 0x1d, code '-2'"
+        );
+        let source = e.source().unwrap();
+        assert_eq!(
+            source.downcast_ref::<std::io::Error>().unwrap().kind(),
+            std::io::ErrorKind::TimedOut
+        );
+
+        // errors of HALs are often only `Debug`
+        #[derive(Debug)]
+        struct BitBangError;
+
+        let e = SensorError::from(BusError::new(BitBangError).at(BusOperation::Write, 0x74));
+        let source = e.source().unwrap();
+        assert_eq!(source.to_string(), "BitBangError");
+        assert!(source.is::<DebugCause>());
+    }
+}
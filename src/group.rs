@@ -0,0 +1,166 @@
+//!
+//! Managing several sensors at once, e.g. one per I2C bus.
+//!
+
+use crate::errors::{ConfigError, SensorError};
+use crate::interface::Interface;
+use crate::{Bme680Data, BME680};
+
+use embedded_hal::blocking::delay::DelayMs;
+
+#[cfg(feature = "linux")]
+use crate::interface::I2cInterface;
+#[cfg(feature = "linux")]
+use crate::Bme680Address;
+#[cfg(feature = "linux")]
+use linux_embedded_hal::{Delay, I2cdev};
+
+const SENSOR_NAME_TAKEN: ConfigError = ConfigError::Duplicate {
+    setting: "sensor name",
+};
+
+///
+/// A set of sensors, each identified by a unique name
+///
+/// Sensors are independent of each other, so any number of them can share an address as
+/// long as they are on different buses.
+///
+pub struct SensorGroup<I, D> {
+    sensors: Vec<(String, BME680<I, D>)>,
+}
+
+impl<I, D> Default for SensorGroup<I, D> {
+    fn default() -> Self {
+        SensorGroup {
+            sensors: Vec::new(),
+        }
+    }
+}
+
+impl<I, D> SensorGroup<I, D>
+where
+    I: Interface,
+    D: DelayMs<u32>,
+{
+    pub fn new() -> Self {
+        SensorGroup::default()
+    }
+
+    ///
+    /// Adds `sensor` as `name`. Fails with `ConfigError::Duplicate` if the name is taken.
+    ///
+    pub fn add(&mut self, name: &str, sensor: BME680<I, D>) -> Result<(), SensorError> {
+        if self.get(name).is_some() {
+            return Err(SENSOR_NAME_TAKEN.into());
+        }
+        self.sensors.push((name.to_string(), sensor));
+        Ok(())
+    }
+
+    ///
+    /// Takes the sensor `name` out of the group.
+    ///
+    pub fn remove(&mut self, name: &str) -> Option<BME680<I, D>> {
+        let position = self.sensors.iter().position(|(n, _)| n == name)?;
+        Some(self.sensors.remove(position).1)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&BME680<I, D>> {
+        self.sensors
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, sensor)| sensor)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut BME680<I, D>> {
+        self.sensors
+            .iter_mut()
+            .find(|(n, _)| n == name)
+            .map(|(_, sensor)| sensor)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.sensors.iter().map(|(name, _)| name.as_str())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&str, &mut BME680<I, D>)> {
+        self.sensors
+            .iter_mut()
+            .map(|(name, sensor)| (name.as_str(), sensor))
+    }
+
+    pub fn len(&self) -> usize {
+        self.sensors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sensors.is_empty()
+    }
+
+    ///
+    /// Reads all sensors one after the other. A failing sensor doesn't stop the others
+    /// from being read.
+    ///
+    pub fn read_all(&mut self) -> Vec<(&str, Result<Bme680Data, SensorError>)> {
+        self.sensors
+            .iter_mut()
+            .map(|(name, sensor)| (name.as_str(), sensor.read_all()))
+            .collect()
+    }
+}
+
+#[cfg(feature = "linux")]
+impl SensorGroup<I2cInterface<I2cdev>, Delay> {
+    ///
+    /// Opens and initializes the sensor at `device_id` on the I2C bus `device` and adds it
+    /// as `<device>@0x<address>`, e.g. `/dev/i2c-1@0x76`. Returns the sensor's name.
+    ///
+    pub fn add_i2c(
+        &mut self,
+        device: &str,
+        device_id: Bme680Address,
+    ) -> Result<String, SensorError> {
+        let name = format!("{}@0x{:x}", device, device_id as u8);
+        if self.get(&name).is_some() {
+            return Err(SENSOR_NAME_TAKEN.into());
+        }
+        let sensor = BME680::initialize(device, device_id)?;
+        self.add(&name, sensor)?;
+        Ok(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::fake_device;
+
+    #[test]
+    fn sensors_are_managed_by_name() {
+        let mut group = SensorGroup::new();
+        // both fakes are at the primary address
+        group.add("/dev/i2c-0@0x76", fake_device(0)).unwrap();
+        group.add("/dev/i2c-1@0x76", fake_device(1)).unwrap();
+        assert!(matches!(
+            group.add("/dev/i2c-1@0x76", fake_device(0)),
+            Err(SensorError::InvalidConfiguration(
+                ConfigError::Duplicate { .. }
+            ))
+        ));
+        assert_eq!(
+            group.names().collect::<Vec<_>>(),
+            ["/dev/i2c-0@0x76", "/dev/i2c-1@0x76"]
+        );
+
+        let readings = group.read_all();
+        assert_eq!(readings.len(), 2);
+        assert!(matches!(
+            readings[1],
+            ("/dev/i2c-1@0x76", Err(SensorError::CommunicationError(_)))
+        ));
+
+        assert!(group.remove("/dev/i2c-0@0x76").is_some());
+        assert!(group.get("/dev/i2c-0@0x76").is_none());
+        assert_eq!(group.len(), 1);
+    }
+}
@@ -0,0 +1,438 @@
+//!
+//! Indoor air quality (IAQ) estimation from gas resistance and humidity.
+//!
+//! The estimator keeps a baseline of the gas resistance in clean air and scores every
+//! measurement against it: 75% of the score comes from how far the gas resistance dropped
+//! below the baseline (VOCs lower the resistance), 25% from how far the relative humidity is
+//! from its optimum. The combined score is mapped to an index from 0 (excellent) to 500
+//! (extremely polluted), similar to the one of Bosch's proprietary BSEC library.
+//!
+//! The gas sensor needs some time to stabilize after power-up, so the first samples are only
+//! used to build up the baseline (burn-in) and the index is reported as `Unreliable`.
+//! To skip this after a restart, save the estimator's `IaqState` and restore it on startup.
+//!
+
+use crate::errors::{ConfigError, SensorError};
+
+#[cfg(feature = "serde")]
+use core::convert::TryFrom;
+
+/// Default relative humidity in % that scores best
+pub const HUMIDITY_OPTIMUM: f32 = 40.0;
+/// Share of the humidity in the air quality score
+pub const HUMIDITY_WEIGHTING: f32 = 0.25;
+/// Highest IAQ index, for the worst air quality
+pub const IAQ_MAX: f32 = 500.0;
+
+/// Samples to take before the baseline is used, 5 minutes at one sample per second
+pub const DEFAULT_BURN_IN_SAMPLES: u32 = 300;
+/// Number of samples the baseline averages over, roughly an hour at one sample per second
+pub const DEFAULT_BASELINE_WINDOW: u32 = 3600;
+
+///
+/// Confidence in an IAQ index
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum IaqAccuracy {
+    /// Burn-in is still running, the index is meaningless
+    #[default]
+    Unreliable = 0,
+    /// The baseline is based on less than one baseline window of samples
+    Low = 1,
+    /// The baseline is based on at least one baseline window
+    Medium = 2,
+    /// The baseline has been tracked for several windows
+    High = 3,
+}
+
+///
+/// An IAQ index between 0 (excellent) and `IAQ_MAX` (extremely polluted)
+///
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Iaq {
+    pub index: f32,
+    pub accuracy: IaqAccuracy,
+}
+
+///
+/// Estimates the IAQ index from a series of gas resistance and humidity measurements,
+/// which should be taken at a steady rate with the same heater settings.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct IaqEstimator {
+    burn_in_samples: u32,
+    baseline_window: u32,
+    samples: u32,
+    gas_baseline: f32,
+    humidity_reference: f32,
+    last: Iaq,
+}
+
+impl Default for IaqEstimator {
+    fn default() -> Self {
+        IaqEstimator::new(DEFAULT_BURN_IN_SAMPLES, DEFAULT_BASELINE_WINDOW)
+    }
+}
+
+impl IaqEstimator {
+    ///
+    /// Creates an estimator that starts scoring after `burn_in_samples` and averages its
+    /// gas baseline over `baseline_window` samples.
+    ///
+    pub fn new(burn_in_samples: u32, baseline_window: u32) -> Self {
+        IaqEstimator {
+            burn_in_samples,
+            baseline_window: baseline_window.max(1),
+            samples: 0,
+            gas_baseline: 0.0,
+            humidity_reference: HUMIDITY_OPTIMUM,
+            last: Iaq::default(),
+        }
+    }
+
+    ///
+    /// Continues estimating from a `state` saved with `state()`.
+    ///
+    pub fn from_state(state: &IaqState) -> Self {
+        let mut estimator = IaqEstimator::new(state.burn_in_samples, state.baseline_window);
+        estimator.samples = state.samples;
+        estimator.gas_baseline = state.gas_baseline;
+        estimator.humidity_reference = state.humidity_reference;
+        estimator.last.accuracy = estimator.accuracy();
+        estimator
+    }
+
+    ///
+    /// What the estimator learned so far, to be restored with `from_state`.
+    ///
+    pub fn state(&self) -> IaqState {
+        IaqState {
+            burn_in_samples: self.burn_in_samples,
+            baseline_window: self.baseline_window,
+            samples: self.samples,
+            gas_baseline: self.gas_baseline,
+            humidity_reference: self.humidity_reference,
+        }
+    }
+
+    ///
+    /// Relative humidity in % that scores best.
+    ///
+    pub fn humidity_reference(&self) -> f32 {
+        self.humidity_reference
+    }
+
+    pub fn set_humidity_reference(&mut self, humidity: f32) {
+        self.humidity_reference = humidity.clamp(1.0, 99.0);
+    }
+
+    ///
+    /// Gas resistance in clean air in Ohm, as learned so far.
+    ///
+    pub fn gas_baseline(&self) -> f32 {
+        self.gas_baseline
+    }
+
+    ///
+    /// Number of samples the estimator has seen.
+    ///
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    ///
+    /// The most recent estimate.
+    ///
+    pub fn last(&self) -> Iaq {
+        self.last
+    }
+
+    ///
+    /// Adds a measurement of the gas resistance in Ohm and the relative humidity in %
+    /// and returns the resulting estimate.
+    ///
+    pub fn update(&mut self, gas_resistance: u32, humidity: f32) -> Iaq {
+        let gas = gas_resistance as f32;
+        self.samples = self.samples.saturating_add(1);
+
+        if self.samples == 1 {
+            self.gas_baseline = gas;
+        } else if self.samples <= self.burn_in_samples {
+            // settle quickly on the sensor's current level
+            let n = self.samples as f32;
+            self.gas_baseline += (gas - self.gas_baseline) / n;
+        } else {
+            // clean air has the highest resistance: follow rising values quickly and
+            // let the baseline drift down slowly, so pollution doesn't become the reference
+            let window = self.baseline_window as f32;
+            let alpha = if gas > self.gas_baseline {
+                4.0 / window
+            } else {
+                1.0 / window
+            };
+            self.gas_baseline += (gas - self.gas_baseline) * alpha.min(1.0);
+        }
+
+        self.last = Iaq {
+            index: weighted_iaq_index(gas, self.gas_baseline, humidity, self.humidity_reference),
+            accuracy: self.accuracy(),
+        };
+        self.last
+    }
+
+    fn accuracy(&self) -> IaqAccuracy {
+        let scored = self.samples.saturating_sub(self.burn_in_samples);
+        if self.samples <= self.burn_in_samples {
+            IaqAccuracy::Unreliable
+        } else if scored < self.baseline_window {
+            IaqAccuracy::Low
+        } else if scored < self.baseline_window.saturating_mul(4) {
+            IaqAccuracy::Medium
+        } else {
+            IaqAccuracy::High
+        }
+    }
+}
+
+///
+/// IAQ index of a gas resistance compared to the clean air `gas_baseline`, at a relative
+/// humidity of `humidity` %.
+///
+pub fn iaq_index(gas_resistance: f32, gas_baseline: f32, humidity: f32) -> f32 {
+    weighted_iaq_index(gas_resistance, gas_baseline, humidity, HUMIDITY_OPTIMUM)
+}
+
+fn weighted_iaq_index(
+    gas_resistance: f32,
+    gas_baseline: f32,
+    humidity: f32,
+    humidity_reference: f32,
+) -> f32 {
+    let humidity = humidity.clamp(0.0, 100.0);
+    let humidity_score = if humidity > humidity_reference {
+        (100.0 - humidity) / (100.0 - humidity_reference)
+    } else {
+        humidity / humidity_reference
+    } * HUMIDITY_WEIGHTING;
+
+    let gas_score = if gas_baseline <= 0.0 || gas_resistance >= gas_baseline {
+        1.0
+    } else {
+        gas_resistance.max(0.0) / gas_baseline
+    } * (1.0 - HUMIDITY_WEIGHTING);
+
+    ((1.0 - humidity_score - gas_score) * IAQ_MAX).clamp(0.0, IAQ_MAX)
+}
+
+/// Version of the `IaqState` serialization format
+pub const IAQ_STATE_VERSION: u8 = 1;
+
+///
+/// Snapshot of an `IaqEstimator`'s learned state, e.g. for surviving restarts.
+///
+/// `to_bytes` produces a little endian byte blob starting with `IAQ_STATE_VERSION`, with the
+/// `serde` feature the state (de-)serializes into a map with a `version` entry.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(into = "VersionedIaqState", try_from = "VersionedIaqState")
+)]
+pub struct IaqState {
+    pub burn_in_samples: u32,
+    pub baseline_window: u32,
+    pub samples: u32,
+    /// Gas resistance in clean air in Ohm
+    pub gas_baseline: f32,
+    /// Relative humidity in % that scores best
+    pub humidity_reference: f32,
+}
+
+impl IaqState {
+    /// Length of the blob written by `to_bytes`
+    pub const ENCODED_LEN: usize = 21;
+
+    pub fn to_bytes(&self) -> [u8; IaqState::ENCODED_LEN] {
+        let mut bytes = [0_u8; IaqState::ENCODED_LEN];
+        bytes[0] = IAQ_STATE_VERSION;
+        bytes[1..5].copy_from_slice(&self.burn_in_samples.to_le_bytes());
+        bytes[5..9].copy_from_slice(&self.baseline_window.to_le_bytes());
+        bytes[9..13].copy_from_slice(&self.samples.to_le_bytes());
+        bytes[13..17].copy_from_slice(&self.gas_baseline.to_le_bytes());
+        bytes[17..21].copy_from_slice(&self.humidity_reference.to_le_bytes());
+        bytes
+    }
+
+    ///
+    /// Parses a blob written by `to_bytes`. Fails with `InvalidLength` for truncated blobs
+    /// and with `InvalidConfiguration` for unknown versions or implausible values.
+    ///
+    pub fn from_bytes(bytes: &[u8]) -> Result<IaqState, SensorError> {
+        if bytes.len() != IaqState::ENCODED_LEN {
+            return Err(SensorError::InvalidLength);
+        }
+        if bytes[0] != IAQ_STATE_VERSION {
+            return Err(ConfigError::Unsupported { setting: "version" }.into());
+        }
+        let word = |i: usize| [bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]];
+        IaqState {
+            burn_in_samples: u32::from_le_bytes(word(1)),
+            baseline_window: u32::from_le_bytes(word(5)),
+            samples: u32::from_le_bytes(word(9)),
+            gas_baseline: f32::from_le_bytes(word(13)),
+            humidity_reference: f32::from_le_bytes(word(17)),
+        }
+        .validated()
+    }
+
+    fn validated(self) -> Result<IaqState, SensorError> {
+        let setting = if !(self.gas_baseline.is_finite() && self.gas_baseline >= 0.0) {
+            "gas_baseline"
+        } else if !(self.humidity_reference > 0.0 && self.humidity_reference < 100.0) {
+            "humidity_reference"
+        } else {
+            return Ok(self);
+        };
+        Err(ConfigError::Unsupported { setting }.into())
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct VersionedIaqState {
+    version: u8,
+    burn_in_samples: u32,
+    baseline_window: u32,
+    samples: u32,
+    gas_baseline: f32,
+    humidity_reference: f32,
+}
+
+#[cfg(feature = "serde")]
+impl From<IaqState> for VersionedIaqState {
+    fn from(state: IaqState) -> Self {
+        VersionedIaqState {
+            version: IAQ_STATE_VERSION,
+            burn_in_samples: state.burn_in_samples,
+            baseline_window: state.baseline_window,
+            samples: state.samples,
+            gas_baseline: state.gas_baseline,
+            humidity_reference: state.humidity_reference,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<VersionedIaqState> for IaqState {
+    type Error = &'static str;
+
+    fn try_from(state: VersionedIaqState) -> Result<Self, Self::Error> {
+        if state.version != IAQ_STATE_VERSION {
+            return Err("unsupported IAQ state version");
+        }
+        IaqState {
+            burn_in_samples: state.burn_in_samples,
+            baseline_window: state.baseline_window,
+            samples: state.samples,
+            gas_baseline: state.gas_baseline,
+            humidity_reference: state.humidity_reference,
+        }
+        .validated()
+        .map_err(|_| "implausible IAQ state")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean_air_scores_best() {
+        assert_eq!(iaq_index(50_000.0, 50_000.0, HUMIDITY_OPTIMUM), 0.0);
+        assert_eq!(iaq_index(80_000.0, 50_000.0, HUMIDITY_OPTIMUM), 0.0);
+        assert_eq!(iaq_index(0.0, 50_000.0, 100.0), IAQ_MAX);
+    }
+
+    #[test]
+    fn humidity_and_gas_are_weighted() {
+        let dry = iaq_index(50_000.0, 50_000.0, 0.0);
+        assert!((dry - HUMIDITY_WEIGHTING * IAQ_MAX).abs() < 0.01);
+        let polluted = iaq_index(25_000.0, 50_000.0, HUMIDITY_OPTIMUM);
+        assert!((polluted - 0.5 * (1.0 - HUMIDITY_WEIGHTING) * IAQ_MAX).abs() < 0.01);
+    }
+
+    #[test]
+    fn burn_in_and_accuracy() {
+        let mut estimator = IaqEstimator::new(10, 20);
+        for _ in 0..10 {
+            let iaq = estimator.update(50_000, HUMIDITY_OPTIMUM);
+            assert_eq!(iaq.accuracy, IaqAccuracy::Unreliable);
+        }
+        assert_eq!(estimator.gas_baseline(), 50_000.0);
+        assert_eq!(
+            estimator.update(50_000, HUMIDITY_OPTIMUM).accuracy,
+            IaqAccuracy::Low
+        );
+        for _ in 0..20 {
+            estimator.update(50_000, HUMIDITY_OPTIMUM);
+        }
+        assert_eq!(estimator.last().accuracy, IaqAccuracy::Medium);
+        for _ in 0..60 {
+            estimator.update(50_000, HUMIDITY_OPTIMUM);
+        }
+        assert_eq!(estimator.last().accuracy, IaqAccuracy::High);
+    }
+
+    #[test]
+    fn pollution_raises_the_index() {
+        let mut estimator = IaqEstimator::new(10, 100);
+        for _ in 0..20 {
+            estimator.update(50_000, HUMIDITY_OPTIMUM);
+        }
+        let clean = estimator.last().index;
+        let polluted = estimator.update(20_000, HUMIDITY_OPTIMUM).index;
+        assert!(polluted > clean + 100.0);
+        // a single bad sample barely moves the baseline
+        assert!(estimator.gas_baseline() > 49_000.0);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut estimator = IaqEstimator::new(10, 100);
+        estimator.set_humidity_reference(45.0);
+        for _ in 0..20 {
+            estimator.update(50_000, 45.0);
+        }
+        let bytes = estimator.state().to_bytes();
+        assert_eq!(bytes[0], IAQ_STATE_VERSION);
+        let restored = IaqEstimator::from_state(&IaqState::from_bytes(&bytes).unwrap());
+        assert_eq!(restored.state(), estimator.state());
+        assert_eq!(restored.last().accuracy, IaqAccuracy::Low);
+
+        assert!(matches!(
+            IaqState::from_bytes(&bytes[1..]),
+            Err(SensorError::InvalidLength)
+        ));
+        let mut unknown_version = bytes;
+        unknown_version[0] = IAQ_STATE_VERSION + 1;
+        assert!(IaqState::from_bytes(&unknown_version).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn state_json_round_trip() {
+        let state = IaqState {
+            burn_in_samples: 300,
+            baseline_window: 3600,
+            samples: 7200,
+            gas_baseline: 81_234.5,
+            humidity_reference: 40.0,
+        };
+        let json = serde_json::to_string(&state).unwrap();
+        assert!(json.contains("\"version\":1"));
+        assert_eq!(serde_json::from_str::<IaqState>(&json).unwrap(), state);
+        let future = json.replace("\"version\":1", "\"version\":2");
+        assert!(serde_json::from_str::<IaqState>(&future).is_err());
+    }
+}
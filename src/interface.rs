@@ -0,0 +1,355 @@
+use crate::errors::BusCause;
+use crate::source::{BME680_SPI_WR_MSK, BME680_TMP_BUFFER_LENGTH};
+use crate::Bme680Address;
+
+use core::convert::Infallible;
+use core::fmt::{self, Debug};
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use embedded_hal::blocking::spi;
+use embedded_hal::digital::v2::OutputPin;
+
+///
+/// Bus the sensor's registers are accessed through
+///
+pub trait Interface {
+    /// Kept as the cause of `SensorError::CommunicationError`, see `BusError`
+    type Error: BusCause;
+
+    /// Reads `data.len()` consecutive registers, starting at `reg_addr`.
+    fn read(&mut self, reg_addr: u8, data: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Writes `data` to the register `reg_addr`. Further registers are
+    /// written as address/value pairs, so `data` is laid out as
+    /// `[value0, addr1, value1, addr2, value2, ...]`.
+    fn write(&mut self, reg_addr: u8, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+///
+/// Bus the sensor's registers are accessed through with `AsyncBME680`, see `Interface`
+///
+#[cfg(feature = "async")]
+#[allow(async_fn_in_trait)]
+pub trait AsyncInterface {
+    /// Kept as the cause of `SensorError::CommunicationError`, see `BusError`
+    type Error: BusCause;
+
+    /// Reads `data.len()` consecutive registers, starting at `reg_addr`.
+    async fn read(&mut self, reg_addr: u8, data: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Writes `data` to the register `reg_addr`, laid out like for `Interface::write`.
+    async fn write(&mut self, reg_addr: u8, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+///
+/// `reg_addr` followed by `data`, as they are sent in one transfer
+///
+fn frame<'a>(
+    buffer: &'a mut [u8; BME680_TMP_BUFFER_LENGTH as usize],
+    reg_addr: u8,
+    data: &[u8],
+) -> &'a [u8] {
+    // the driver never writes more than BME680_TMP_BUFFER_LENGTH bytes at once
+    buffer[0] = reg_addr;
+    buffer[1..=data.len()].copy_from_slice(data);
+    &buffer[..=data.len()]
+}
+
+///
+/// Sensor attached to an I2C bus
+///
+pub struct I2cInterface<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C> I2cInterface<I2C> {
+    pub fn new(i2c: I2C, address: Bme680Address) -> I2cInterface<I2C> {
+        I2cInterface {
+            i2c,
+            address: address as u8,
+        }
+    }
+
+    /// Gives back the underlying bus.
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
+}
+
+impl<I2C, E> Interface for I2cInterface<I2C>
+where
+    I2C: WriteRead<Error = E> + Write<Error = E>,
+    E: BusCause,
+{
+    type Error = E;
+
+    fn read(&mut self, reg_addr: u8, data: &mut [u8]) -> Result<(), E> {
+        self.i2c.write_read(self.address, &[reg_addr], data)
+    }
+
+    fn write(&mut self, reg_addr: u8, data: &[u8]) -> Result<(), E> {
+        let mut buffer = [0_u8; BME680_TMP_BUFFER_LENGTH as usize];
+        self.i2c
+            .write(self.address, frame(&mut buffer, reg_addr, data))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<I2C> AsyncInterface for I2cInterface<I2C>
+where
+    I2C: embedded_hal_async::i2c::I2c,
+    I2C::Error: BusCause,
+{
+    type Error = I2C::Error;
+
+    async fn read(&mut self, reg_addr: u8, data: &mut [u8]) -> Result<(), I2C::Error> {
+        self.i2c.write_read(self.address, &[reg_addr], data).await
+    }
+
+    async fn write(&mut self, reg_addr: u8, data: &[u8]) -> Result<(), I2C::Error> {
+        let mut buffer = [0_u8; BME680_TMP_BUFFER_LENGTH as usize];
+        self.i2c
+            .write(self.address, frame(&mut buffer, reg_addr, data))
+            .await
+    }
+}
+
+///
+/// Sensor attached to a SPI bus
+///
+/// In SPI mode the register map is split into two memory pages of 128 registers each.
+/// Addresses passed to this interface are already in their 7 bit SPI form (including the
+/// read bit) and the driver takes care of switching pages via `BME680_MEM_PAGE_ADDR`.
+///
+pub struct SpiInterface<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+}
+
+impl<SPI, CS> SpiInterface<SPI, CS> {
+    pub fn new(spi: SPI, cs: CS) -> SpiInterface<SPI, CS> {
+        SpiInterface { spi, cs }
+    }
+
+    /// Gives back the underlying bus and chip select pin.
+    pub fn release(self) -> (SPI, CS) {
+        (self.spi, self.cs)
+    }
+}
+
+///
+/// Errors of the SPI bus or the chip select pin
+///
+#[derive(Debug)]
+pub enum SpiError<S, P> {
+    Spi(S),
+    ChipSelect(P),
+    /// A read runs past the last register of the memory page
+    PageOverflow,
+}
+
+impl<S: Debug, P: Debug> fmt::Display for SpiError<S, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpiError::Spi(e) => write!(f, "SPI error: {:?}", e),
+            SpiError::ChipSelect(e) => write!(f, "chip select error: {:?}", e),
+            SpiError::PageOverflow => write!(f, "read past the end of the memory page"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<S, P> std::error::Error for SpiError<S, P>
+where
+    S: std::error::Error + 'static,
+    P: std::error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SpiError::Spi(e) => Some(e),
+            SpiError::ChipSelect(e) => Some(e),
+            SpiError::PageOverflow => None,
+        }
+    }
+}
+
+impl<SPI, CS> SpiInterface<SPI, CS>
+where
+    CS: OutputPin,
+{
+    fn transaction<T, E>(
+        &mut self,
+        f: impl FnOnce(&mut SPI) -> Result<T, E>,
+    ) -> Result<T, SpiError<E, CS::Error>> {
+        self.cs.set_low().map_err(SpiError::ChipSelect)?;
+        let result = f(&mut self.spi).map_err(SpiError::Spi);
+        self.cs.set_high().map_err(SpiError::ChipSelect)?;
+        result
+    }
+}
+
+impl<SPI, CS, E> Interface for SpiInterface<SPI, CS>
+where
+    SPI: spi::Transfer<u8, Error = E> + spi::Write<u8, Error = E>,
+    CS: OutputPin,
+    CS::Error: BusCause,
+    E: BusCause,
+{
+    type Error = SpiError<E, CS::Error>;
+
+    fn read(&mut self, reg_addr: u8, data: &mut [u8]) -> Result<(), Self::Error> {
+        // address and data have to go out in one transfer, since chip selects driven by
+        // the SPI peripheral (like spidev's) are released between transfers
+        let page_offset = (reg_addr & BME680_SPI_WR_MSK) as usize;
+        if page_offset + data.len() > BME680_SPI_WR_MSK as usize + 1 {
+            return Err(SpiError::PageOverflow);
+        }
+        let mut buffer = [0_u8; BME680_TMP_BUFFER_LENGTH as usize];
+        let chunk_len = buffer.len() - 1;
+        for (i, chunk) in data.chunks_mut(chunk_len).enumerate() {
+            // stays within the page, so the read bit is kept
+            buffer[0] = reg_addr + (i * chunk_len) as u8;
+            self.transaction(|spi| spi.transfer(&mut buffer[..=chunk.len()]).map(|_| ()))?;
+            chunk.copy_from_slice(&buffer[1..=chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, reg_addr: u8, data: &[u8]) -> Result<(), Self::Error> {
+        let mut buffer = [0_u8; BME680_TMP_BUFFER_LENGTH as usize];
+        self.transaction(|spi| spi.write(frame(&mut buffer, reg_addr, data)))
+    }
+}
+
+///
+/// With an async `SpiDevice`, the device drives the chip select, so the sensor is attached
+/// with `HardwareChipSelect`.
+///
+#[cfg(feature = "async")]
+impl<SPI> AsyncInterface for SpiInterface<SPI, HardwareChipSelect>
+where
+    SPI: embedded_hal_async::spi::SpiDevice,
+    SPI::Error: BusCause,
+{
+    type Error = SPI::Error;
+
+    async fn read(&mut self, reg_addr: u8, data: &mut [u8]) -> Result<(), SPI::Error> {
+        use embedded_hal_async::spi::Operation;
+
+        // the chip select stays asserted for all operations of a transaction
+        self.spi
+            .transaction(&mut [Operation::Write(&[reg_addr]), Operation::Read(data)])
+            .await
+    }
+
+    async fn write(&mut self, reg_addr: u8, data: &[u8]) -> Result<(), SPI::Error> {
+        let mut buffer = [0_u8; BME680_TMP_BUFFER_LENGTH as usize];
+        self.spi.write(frame(&mut buffer, reg_addr, data)).await
+    }
+}
+
+///
+/// Placeholder for a chip select that is driven by the SPI peripheral itself, e.g. spidev's
+/// or an async `SpiDevice`'s
+///
+pub struct HardwareChipSelect;
+
+impl OutputPin for HardwareChipSelect {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{
+        BME680_CHIP_ID, BME680_CHIP_ID_ADDR, BME680_SOFT_RESET_ADDR, BME680_SOFT_RESET_CMD,
+    };
+    use crate::testing::NoDelay;
+    use crate::BME680;
+
+    /// Error of a HAL that is only `Debug`
+    #[derive(Debug)]
+    struct MockError;
+
+    /// I2C bus that records the transfers
+    struct MockI2c {
+        registers: [u8; 256],
+        reads: Vec<(u8, u8, usize)>,
+        writes: Vec<(u8, Vec<u8>)>,
+    }
+
+    impl MockI2c {
+        fn new() -> Self {
+            let mut registers = [0; 256];
+            for (reg_addr, value) in registers.iter_mut().enumerate() {
+                *value = !(reg_addr as u8);
+            }
+            registers[BME680_CHIP_ID_ADDR as usize] = BME680_CHIP_ID;
+            MockI2c {
+                registers,
+                reads: Vec::new(),
+                writes: Vec::new(),
+            }
+        }
+    }
+
+    impl WriteRead for MockI2c {
+        type Error = MockError;
+
+        fn write_read(
+            &mut self,
+            address: u8,
+            bytes: &[u8],
+            buffer: &mut [u8],
+        ) -> Result<(), MockError> {
+            let reg_addr = bytes[0];
+            self.reads.push((address, reg_addr, buffer.len()));
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte = self.registers[reg_addr as usize + i];
+            }
+            Ok(())
+        }
+    }
+
+    impl Write for MockI2c {
+        type Error = MockError;
+
+        fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), MockError> {
+            self.writes.push((address, bytes.to_vec()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn i2c_transfers() {
+        let mut interface = I2cInterface::new(MockI2c::new(), Bme680Address::Secondary);
+        let mut data = [0_u8; 3];
+        interface.read(0x1d, &mut data).unwrap();
+        assert_eq!(data, [!0x1d, !0x1e, !0x1f]);
+        interface.write(0x72, &[0x01, 0x74, 0x25]).unwrap();
+
+        let i2c = interface.release();
+        assert_eq!(i2c.reads, [(0x77, 0x1d, 3)]);
+        assert_eq!(i2c.writes, [(0x77, vec![0x72, 0x01, 0x74, 0x25])]);
+    }
+
+    #[test]
+    fn sensor_on_generic_i2c_bus() {
+        let sensor = BME680::new_i2c(MockI2c::new(), Bme680Address::Primary, NoDelay).unwrap();
+        let (interface, NoDelay) = sensor.release();
+        let i2c = interface.release();
+        assert_eq!(
+            i2c.writes[0],
+            (0x76, vec![BME680_SOFT_RESET_ADDR, BME680_SOFT_RESET_CMD])
+        );
+        assert!(i2c.reads.contains(&(0x76, BME680_CHIP_ID_ADDR, 1)));
+    }
+}
@@ -1,44 +1,151 @@
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+#[cfg(all(feature = "async", feature = "ffi"))]
+compile_error!("the async API is only available with the native driver, disable \"ffi\"");
+
+#[cfg(feature = "async")]
+pub mod asynch;
+#[cfg(feature = "ffi")]
+mod bosch;
+pub mod compensation;
+pub mod config;
+mod controller;
 pub mod devices;
 pub mod errors;
+#[cfg(feature = "std")]
+pub mod group;
+#[cfg(feature = "ffi")]
 mod helpers;
+pub mod iaq;
+pub mod interface;
+#[cfg(not(feature = "ffi"))]
+mod native;
+#[cfg(feature = "std")]
+pub mod sampling;
+#[cfg(feature = "std")]
+#[allow(dead_code)]
 mod sensors;
+#[cfg(all(feature = "serde", feature = "std"))]
+pub mod setup;
 mod source;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "std")]
+pub mod trace;
+
+#[cfg(feature = "ffi")]
+use bosch as driver;
+#[cfg(not(feature = "ffi"))]
+use native as driver;
 
+use config::{Bme680Config, ConfigRegisters};
+use controller::{block_on, Access, Controller};
 use devices::{AirQualitySensor, Barometer, Thermometer};
-use errors::SensorError;
+use errors::{BusCause, BusError, BusOperation, ConfigError, SensorError};
+use iaq::Iaq;
+#[cfg(feature = "linux")]
+use interface::HardwareChipSelect;
+use interface::{I2cInterface, Interface, SpiInterface};
 use source::*;
 
-use i2cdev::core::*;
-use i2cdev::linux::LinuxI2CDevice;
-use log::{debug, error, info, trace, warn};
-use std::cell::RefCell;
-use std::cmp;
-use std::collections::BTreeMap;
-use std::ptr;
-use std::thread::sleep;
-use std::time::Duration;
+use core::fmt;
+use core::str::FromStr;
+use embedded_hal::blocking::delay::DelayMs;
+#[cfg(feature = "linux")]
+use linux_embedded_hal::spidev::{SpiModeFlags, SpidevOptions};
+#[cfg(feature = "linux")]
+use linux_embedded_hal::{Delay, I2cdev, Spidev};
+#[cfg(feature = "linux")]
+use log::info;
+use log::{error, trace};
+#[cfg(feature = "std")]
+use std::time::{Instant, SystemTime};
 
 ///
 /// Over-sampling settings
 ///
+/// Written as `"none"`, `"1x"`, `"2x"`, `"4x"`, `"8x"` and `"16x"`, by `Display`,
+/// `FromStr` and with the `serde` feature.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Oversampling {
+    #[cfg_attr(feature = "serde", serde(rename = "none"))]
     None = 0,
+    #[cfg_attr(feature = "serde", serde(rename = "1x"))]
     _1X = 1,
+    #[cfg_attr(feature = "serde", serde(rename = "2x"))]
     _2X = 2,
+    #[cfg_attr(feature = "serde", serde(rename = "4x"))]
     _4X = 3,
+    #[cfg_attr(feature = "serde", serde(rename = "8x"))]
     _8X = 4,
+    #[cfg_attr(feature = "serde", serde(rename = "16x"))]
     _16X = 5,
 }
 
-impl From<u8> for Oversampling {
-    fn from(os: u8) -> Self {
-        match os {
-            1 => Oversampling::_1X,
-            2 => Oversampling::_2X,
-            4 => Oversampling::_4X,
-            8 => Oversampling::_8X,
-            16 => Oversampling::_16X,
-            _ => Oversampling::None,
+impl Oversampling {
+    pub const ALL: [Oversampling; 6] = [
+        Oversampling::None,
+        Oversampling::_1X,
+        Oversampling::_2X,
+        Oversampling::_4X,
+        Oversampling::_8X,
+        Oversampling::_16X,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Oversampling::None => "none",
+            Oversampling::_1X => "1x",
+            Oversampling::_2X => "2x",
+            Oversampling::_4X => "4x",
+            Oversampling::_8X => "8x",
+            Oversampling::_16X => "16x",
+        }
+    }
+
+    ///
+    /// Value of the sensor's oversampling fields. Fields hold 3 bits, the values above
+    /// `_16X` select 16x as well.
+    ///
+    pub fn from_register(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Oversampling::None),
+            1 => Some(Oversampling::_1X),
+            2 => Some(Oversampling::_2X),
+            3 => Some(Oversampling::_4X),
+            4 => Some(Oversampling::_8X),
+            5..=7 => Some(Oversampling::_16X),
+            _ => None,
+        }
+    }
+
+    pub fn to_register(self) -> u8 {
+        self as u8
+    }
+
+    ///
+    /// From a number of samples, see `factor`. `None` for numbers the sensor can't average.
+    ///
+    pub fn from_factor(factor: u8) -> Option<Self> {
+        Oversampling::ALL
+            .iter()
+            .copied()
+            .find(|oversampling| oversampling.factor() == factor)
+    }
+
+    ///
+    /// Number of samples averaged per measurement, 0 if the measurement is skipped.
+    ///
+    pub fn factor(self) -> u8 {
+        match self {
+            Oversampling::None => 0,
+            Oversampling::_1X => 1,
+            Oversampling::_2X => 2,
+            Oversampling::_4X => 4,
+            Oversampling::_8X => 8,
+            Oversampling::_16X => 16,
         }
     }
 }
@@ -46,17 +153,70 @@ impl From<u8> for Oversampling {
 ///
 ///  IIR filter settings
 ///
+/// Written as `"size_0"`, `"size_1"`, `"size_3"`, ... `"size_127"`, by `Display`,
+/// `FromStr` and with the `serde` feature.
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FilterSize {
+    #[cfg_attr(feature = "serde", serde(rename = "size_0"))]
     Size0 = 0,
+    #[cfg_attr(feature = "serde", serde(rename = "size_1"))]
     Size1 = 1,
+    #[cfg_attr(feature = "serde", serde(rename = "size_3"))]
     Size3 = 2,
+    #[cfg_attr(feature = "serde", serde(rename = "size_7"))]
     Size7 = 3,
+    #[cfg_attr(feature = "serde", serde(rename = "size_15"))]
     Size15 = 4,
+    #[cfg_attr(feature = "serde", serde(rename = "size_31"))]
     Size31 = 5,
+    #[cfg_attr(feature = "serde", serde(rename = "size_63"))]
     Size63 = 6,
+    #[cfg_attr(feature = "serde", serde(rename = "size_127"))]
     Size127 = 7,
 }
 
+impl FilterSize {
+    pub const ALL: [FilterSize; 8] = [
+        FilterSize::Size0,
+        FilterSize::Size1,
+        FilterSize::Size3,
+        FilterSize::Size7,
+        FilterSize::Size15,
+        FilterSize::Size31,
+        FilterSize::Size63,
+        FilterSize::Size127,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FilterSize::Size0 => "size_0",
+            FilterSize::Size1 => "size_1",
+            FilterSize::Size3 => "size_3",
+            FilterSize::Size7 => "size_7",
+            FilterSize::Size15 => "size_15",
+            FilterSize::Size31 => "size_31",
+            FilterSize::Size63 => "size_63",
+            FilterSize::Size127 => "size_127",
+        }
+    }
+
+    ///
+    /// Value of the sensor's filter field, `None` for values that don't fit its 3 bits.
+    ///
+    pub fn from_register(value: u8) -> Option<Self> {
+        match value {
+            0..=7 => Some(FilterSize::from(value)),
+            _ => None,
+        }
+    }
+
+    pub fn to_register(self) -> u8 {
+        self as u8
+    }
+}
+
 impl From<u8> for FilterSize {
     fn from(filter: u8) -> Self {
         match filter {
@@ -72,258 +232,567 @@ impl From<u8> for FilterSize {
     }
 }
 
-#[derive(Copy, Clone)]
-pub enum Bme680Address {
-    Primary = BME680_I2C_ADDR_PRIMARY as isize,
-    Secondary = BME680_I2C_ADDR_SECONDARY as isize,
+impl fmt::Display for Oversampling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
 }
 
-impl Default for Bme680Address {
-    fn default() -> Self {
-        Bme680Address::Primary
+impl FromStr for Oversampling {
+    type Err = ConfigError;
+
+    fn from_str(name: &str) -> Result<Self, ConfigError> {
+        Oversampling::ALL
+            .iter()
+            .copied()
+            .find(|oversampling| oversampling.name() == name)
+            .ok_or(ConfigError::Unsupported {
+                setting: "oversampling",
+            })
     }
 }
 
-thread_local!(static DEVICES: RefCell<BTreeMap<u8, LinuxI2CDevice>> = RefCell::new(BTreeMap::new()));
+impl fmt::Display for FilterSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
 
-unsafe extern "C" fn write(dev_id: u8, reg_addr: u8, data: *mut u8, len: u16) -> i8 {
-    DEVICES.with(|devices| {
-        devices.borrow_mut().get_mut(&dev_id).map_or(1, |mut dev| {
-            let d = std::slice::from_raw_parts(data, len as usize);
-            dev.smbus_write_i2c_block_data(reg_addr, &d)
-                .map(|_| 0)
-                .map_err(|e| {
-                    error!("error: {:?}", e);
-                    e
-                })
-                .unwrap_or(1)
-        })
-    })
-}
-
-unsafe extern "C" fn read(dev_id: u8, reg_addr: u8, data: *mut u8, len: u16) -> i8 {
-    DEVICES.with(|devices| {
-        devices.borrow_mut().get_mut(&dev_id).map_or(1, |mut dev| {
-            dev.smbus_read_i2c_block_data(reg_addr, len as u8)
-                .map(|d| {
-                    //let mut out_data = std::slice::from_raw_parts_mut(data,d.len());
-                    ptr::copy_nonoverlapping(d.as_ptr(), data, cmp::min(len as usize, d.len()));
-                    //out_data = &d.clone();
-                    0
-                })
-                .unwrap_or(1)
+impl FromStr for FilterSize {
+    type Err = ConfigError;
+
+    fn from_str(name: &str) -> Result<Self, ConfigError> {
+        FilterSize::ALL
+            .iter()
+            .copied()
+            .find(|filter| filter.name() == name)
+            .ok_or(ConfigError::Unsupported { setting: "filter" })
+    }
+}
+
+/// Heater target temperature of a newly initialized sensor in °C
+pub const HEATER_TEMPERATURE_DEFAULT: u16 = 320;
+/// Heating duration of a newly initialized sensor in ms
+pub const HEATER_DURATION_DEFAULT: u16 = 150;
+/// Lowest supported heater target temperature in °C
+pub const HEATER_TEMPERATURE_MIN: u16 = 200;
+/// Highest supported heater target temperature in °C
+pub const HEATER_TEMPERATURE_MAX: u16 = 400;
+/// Longest supported heating duration in ms
+pub const HEATER_DURATION_MAX: u16 = 4032;
+/// Number of heater set-points the sensor can hold
+pub const HEATER_PROFILE_MAX_STEPS: usize = BME680_NBCONV_MAX as usize;
+
+fn check_range(setting: &'static str, value: u32, min: u32, max: u32) -> Result<(), ConfigError> {
+    if (min..=max).contains(&value) {
+        Ok(())
+    } else {
+        Err(ConfigError::OutOfRange {
+            setting,
+            value,
+            min,
+            max,
         })
-    })
+    }
 }
 
-unsafe extern "C" fn delay(ms: u32) {
-    sleep(Duration::from_millis(ms as u64));
+fn check_heater_temperature(temperature: u16) -> Result<(), ConfigError> {
+    check_range(
+        "heater_temperature",
+        temperature as u32,
+        HEATER_TEMPERATURE_MIN as u32,
+        HEATER_TEMPERATURE_MAX as u32,
+    )
 }
 
-#[derive(Debug)]
-pub struct Bme680Data {
-    pub temperature: f32,
-    pub pressure: u32,
-    pub humidity: f32,
+fn check_heater_duration(duration: u16) -> Result<(), ConfigError> {
+    check_range(
+        "heater_duration",
+        duration as u32,
+        1,
+        HEATER_DURATION_MAX as u32,
+    )
+}
+
+fn check_heater_step(temperature: u16, duration: u16) -> Result<(), ConfigError> {
+    check_heater_temperature(temperature)?;
+    check_heater_duration(duration)
+}
+
+///
+/// A heater set-point: target temperature in °C and heating duration in ms
+///
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct HeaterStep {
+    pub temperature: u16,
+    pub duration: u16,
+}
+
+///
+/// Gas resistance measured at one step of a heater profile
+///
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct GasScanReading {
+    /// Index of the heater set-point the sensor used for this measurement
+    pub gas_index: u8,
+    pub step: HeaterStep,
+    /// `None` if the sensor flagged the measurement as invalid
     pub gas_resistance: Option<u32>,
 }
 
-pub struct BME680 {
-    native_device: bme680_dev,
-    reset: bool,
-    measure_period: u16,
-    settings: u16,
+///
+/// Results of a heater profile scan, one reading per step
+///
+#[derive(Debug, Default, Copy, Clone)]
+pub struct GasScan {
+    readings: [GasScanReading; HEATER_PROFILE_MAX_STEPS],
+    len: usize,
+}
+
+impl GasScan {
+    pub fn readings(&self) -> &[GasScanReading] {
+        &self.readings[..self.len]
+    }
 }
 
-impl BME680 {
-    pub(crate) fn raw_init(dev: bme680_dev) -> BME680 {
-        BME680 {
-            native_device: dev,
-            measure_period: 20, // some value, will be changed on first read
-            reset: true,
-            settings: BME680_OST_SEL
-                | BME680_OSP_SEL
-                | BME680_OSH_SEL
-                | BME680_FILTER_SEL
-                | BME680_GAS_SENSOR_SEL,
+///
+/// I2C address of the sensor, selected by its SDO pin
+///
+/// Written as `"primary"` (0x76) and `"secondary"` (0x77), by `Display` and with the
+/// `serde` feature. `FromStr` takes the hexadecimal addresses as well.
+///
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Bme680Address {
+    #[default]
+    Primary = BME680_I2C_ADDR_PRIMARY as isize,
+    Secondary = BME680_I2C_ADDR_SECONDARY as isize,
+}
+
+impl Bme680Address {
+    pub fn name(self) -> &'static str {
+        match self {
+            Bme680Address::Primary => "primary",
+            Bme680Address::Secondary => "secondary",
         }
     }
+}
 
-    pub fn initialize(device: &str, device_id: Bme680Address) -> Result<BME680, SensorError> {
-        DEVICES.with(|devices_cell| {
-            devices_cell.borrow_mut().insert(
-                device_id as u8,
-                LinuxI2CDevice::new(device, device_id as u16).unwrap(),
-            );
-        });
+impl fmt::Display for Bme680Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
 
-        let mut native_dev = bme680_dev {
-            chip_id: BME680_CHIP_ID,
-            dev_id: device_id as u8, // i2c address
-            intf: bme680_intf_BME680_I2C_INTF,
-            mem_page: 0,
-            amb_temp: 25, // according to specs
-            calib: bme680_calib_data::default(),
-            tph_sett: bme680_tph_sett::default(),
-            gas_sett: bme680_gas_sett::default(),
-            power_mode: BME680_SLEEP_MODE, // sleep mode, 0x01 forced mode,
-            new_fields: 0,
-            info_msg: 0,
-            read: Some(read),
-            write: Some(write),
-            delay_ms: Some(delay),
-            com_rslt: 0,
-        };
+impl FromStr for Bme680Address {
+    type Err = ConfigError;
 
-        let init_result;
-        unsafe {
-            init_result = bme680_init(&mut native_dev);
-        }
-        if init_result != BME680_OK {
-            info!("failed to initialize '{}'", device);
-            Err(SensorError::from(init_result))
-        } else {
-            info!("successfully initialized '{}'", device);
-            Ok(BME680::raw_init(native_dev))
+    fn from_str(name: &str) -> Result<Self, ConfigError> {
+        match name {
+            "primary" | "0x76" => Ok(Bme680Address::Primary),
+            "secondary" | "0x77" => Ok(Bme680Address::Secondary),
+            _ => Err(ConfigError::Unsupported { setting: "address" }),
         }
     }
+}
 
-    fn activate_device(&mut self) -> Result<(), SensorError> {
-        let mut rslt;
-        self.native_device.power_mode = BME680_FORCED_MODE;
-        let mut retries = 10;
-        loop {
-            unsafe {
-                rslt = bme680_set_sensor_mode(&mut self.native_device);
-            }
-            if retries == 0 || rslt == BME680_OK {
-                break;
+/// Object-safe view on a sensor's interface and delay provider, for use by
+/// the driver backends
+pub(crate) trait Bus {
+    fn read(&mut self, reg_addr: u8, data: &mut [u8]) -> i8;
+    fn write(&mut self, reg_addr: u8, data: &[u8]) -> i8;
+    fn delay_ms(&mut self, period: u32);
+}
+
+struct Transport<I, D> {
+    interface: I,
+    delay: D,
+    /// Last failed access, as the driver backends only report a result code
+    error: Option<BusError>,
+}
+
+impl<I, D> Transport<I, D> {
+    fn result<E: BusCause>(
+        &mut self,
+        operation: BusOperation,
+        reg_addr: u8,
+        result: Result<(), E>,
+    ) -> i8 {
+        match result {
+            Ok(()) => 0,
+            Err(e) => {
+                error!("error: {:?}", e);
+                self.error = Some(BusError::new(e).at(operation, reg_addr));
+                1
             }
-            retries -= 1;
-            debug!("Retrying setting the sensor to forced: {} left", retries);
         }
-        if rslt == BME680_OK {
-            trace!("sensor set to FORCED");
-            Ok(())
-        } else {
-            let e = SensorError::from(rslt);
-            trace!("error setting sensor to forced: '{}'", e);
-            Err(e)
+    }
+}
+
+impl<I: Interface, D: DelayMs<u32>> Bus for Transport<I, D> {
+    fn read(&mut self, reg_addr: u8, data: &mut [u8]) -> i8 {
+        let result = self.interface.read(reg_addr, data);
+        self.result(BusOperation::Read, reg_addr, result)
+    }
+
+    fn write(&mut self, reg_addr: u8, data: &[u8]) -> i8 {
+        let result = self.interface.write(reg_addr, data);
+        self.result(BusOperation::Write, reg_addr, result)
+    }
+
+    fn delay_ms(&mut self, period: u32) {
+        self.delay.delay_ms(period);
+    }
+}
+
+impl<I: Interface, D: DelayMs<u32>> Access for Transport<I, D> {
+    async fn init(&mut self, dev: &mut bme680_dev) -> i8 {
+        driver::init(dev, self)
+    }
+
+    async fn set_sensor_settings(&mut self, desired_settings: u16, dev: &mut bme680_dev) -> i8 {
+        driver::set_sensor_settings(desired_settings, dev, self)
+    }
+
+    async fn set_sensor_mode(&mut self, dev: &mut bme680_dev) -> i8 {
+        driver::set_sensor_mode(dev, self)
+    }
+
+    async fn get_sensor_data(&mut self, data: &mut bme680_field_data, dev: &mut bme680_dev) -> i8 {
+        driver::get_sensor_data(data, dev, self)
+    }
+
+    async fn read_regs(&mut self, reg_addr: u8, reg_data: &mut [u8], dev: &mut bme680_dev) -> i8 {
+        driver::read_regs(reg_addr, reg_data, dev, self)
+    }
+
+    async fn write_regs(&mut self, reg_addr: &[u8], reg_data: &[u8], dev: &mut bme680_dev) -> i8 {
+        driver::write_regs(reg_addr, reg_data, dev, self)
+    }
+
+    async fn delay_ms(&mut self, period: u32) {
+        self.delay.delay_ms(period);
+    }
+
+    fn take_error(&mut self) -> Option<BusError> {
+        self.error.take()
+    }
+}
+
+///
+/// A compensated measurement
+///
+/// With the `serde` feature, it (de-)serializes into a map of its fields.
+///
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Bme680Data {
+    /// Temperature in °C
+    pub temperature: f32,
+    /// Pressure in Pa
+    pub pressure: u32,
+    /// Relative humidity in %
+    pub humidity: f32,
+    /// Gas resistance in Ohm, `None` without a valid gas measurement
+    pub gas_resistance: Option<u32>,
+    /// Index of the measurement, as counted by the sensor
+    pub meas_index: u8,
+    /// Index of the heater set-point used for the gas measurement
+    pub gas_index: u8,
+    /// Whether the heater reached its target temperature
+    pub heat_stable: bool,
+    /// Whether the sensor flagged the gas measurement as valid
+    pub gas_valid: bool,
+    /// Settings the measurement was taken with
+    pub settings: Bme680Config,
+}
+
+impl Bme680Data {
+    pub(crate) fn from_field_data(data: &bme680_field_data, settings: Bme680Config) -> Self {
+        let gas_valid = data.status & BME680_GASM_VALID_MSK != 0;
+        Bme680Data {
+            pressure: data.pressure,
+            temperature: data.temperature as f32 / 100.0,
+            humidity: data.humidity as f32 / 1000.0,
+            gas_resistance: if gas_valid && settings.gas_enabled {
+                Some(data.gas_resistance)
+            } else {
+                None
+            },
+            meas_index: data.meas_index,
+            gas_index: data.gas_index,
+            heat_stable: data.status & BME680_HEAT_STAB_MSK != 0,
+            gas_valid,
+            settings,
         }
     }
 
-    fn read_prep(&mut self) -> Result<(), SensorError> {
-        let mut sleep_period = 20_u16;
+    fn heater_unstable(&self) -> bool {
+        self.settings.gas_enabled && self.settings.heater_enabled && !self.heat_stable
+    }
+}
+
+///
+/// What to do with gas measurements taken before the heater reached its target temperature
+///
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum UnstableHeaterPolicy {
+    /// Return them, `heat_stable` tells them apart
+    #[default]
+    Keep,
+    /// Return them without `gas_resistance`
+    Discard,
+    /// Measure again, up to the given number of times, then discard
+    Retry(u8),
+}
 
-        self.native_device.gas_sett.heatr_temp = 320;
-        self.native_device.gas_sett.heatr_dur = 150;
-        let rslt;
-        unsafe {
-            rslt = bme680_set_sensor_settings(self.settings, &mut self.native_device);
+impl UnstableHeaterPolicy {
+    pub(crate) fn retries(&self) -> u8 {
+        match self {
+            UnstableHeaterPolicy::Retry(retries) => *retries,
+            _ => 0,
         }
+    }
 
-        self.activate_device()?;
+    pub(crate) fn needs_retry(&self, data: &Bme680Data) -> bool {
+        self.retries() > 0 && data.heater_unstable()
+    }
 
-        unsafe {
-            bme680_get_profile_dur(&mut sleep_period, &self.native_device);
+    pub(crate) fn apply(&self, mut data: Bme680Data) -> Bme680Data {
+        if *self != UnstableHeaterPolicy::Keep && data.heater_unstable() {
+            trace!("discarding gas measurement with unstable heater");
+            data.gas_resistance = None;
         }
+        data
+    }
+}
 
-        self.measure_period = sleep_period;
-        if rslt == BME680_OK {
-            trace!("sensor prepared");
-            self.reset = false;
-            Ok(())
-        } else {
-            let e = SensorError::from(rslt);
-            trace!("sensor preparation error: '{}'", e);
-            Err(e)
+///
+/// A value with the time it was measured at
+///
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct Timestamped<T> {
+    /// For ordering and computing intervals, unaffected by clock changes
+    pub monotonic: Instant,
+    pub wall_clock: SystemTime,
+    pub value: T,
+}
+
+#[cfg(feature = "std")]
+impl<T> Timestamped<T> {
+    pub(crate) fn now(value: T) -> Self {
+        Timestamped {
+            monotonic: Instant::now(),
+            wall_clock: SystemTime::now(),
+            value,
         }
     }
+}
 
-    pub fn read_all(&mut self) -> Result<Bme680Data, SensorError> {
-        let mut data = bme680_field_data::default();
-        if self.reset {
-            self.read_prep()?;
+///
+/// A BME680 sensor
+///
+/// Every instance owns its bus interface and delay provider and all driver calls go
+/// through them, so sensors can be moved between threads (`BME680` is `Send` as long as
+/// `I` and `D` are) and shared behind a `Mutex`.
+///
+pub struct BME680<I, D> {
+    transport: Transport<I, D>,
+    controller: Controller,
+}
+
+#[cfg(feature = "linux")]
+impl BME680<I2cInterface<I2cdev>, Delay> {
+    ///
+    /// Opens the I2C bus at `device` (e.g. `/dev/i2c-1`) and initializes the sensor on it.
+    ///
+    pub fn initialize(device: &str, device_id: Bme680Address) -> Result<Self, SensorError> {
+        let i2c = I2cdev::new(device).map_err(|e| {
+            error!("failed to open '{}': {:?}", device, e);
+            BusError::new(e)
+        })?;
+        let sensor = BME680::new_i2c(i2c, device_id, Delay);
+        if sensor.is_ok() {
+            info!("successfully initialized '{}'", device);
         } else {
-            self.activate_device()?;
-        }
-        let rslt;
-        unsafe {
-            delay(self.measure_period as u32);
-            rslt = bme680_get_sensor_data(&mut data, &mut self.native_device);
+            info!("failed to initialize '{}'", device);
         }
-        if rslt == BME680_OK {
-            Ok(Bme680Data {
-                pressure: data.pressure,
-                temperature: data.temperature as f32 / 100.0,
-                humidity: data.humidity as f32 / 1000.0,
-                gas_resistance: if (data.status & BME680_GASM_VALID_MSK) == 0 {
-                    Some(data.gas_resistance)
-                } else {
-                    None
-                },
-            })
+        sensor
+    }
+}
+
+#[cfg(feature = "linux")]
+impl BME680<SpiInterface<Spidev, HardwareChipSelect>, Delay> {
+    ///
+    /// Opens the SPI device at `device` (e.g. `/dev/spidev0.0`) and initializes the sensor on it.
+    ///
+    pub fn initialize_spi(device: &str) -> Result<Self, SensorError> {
+        let mut spi = Spidev::open(device).map_err(|e| {
+            error!("failed to open '{}': {:?}", device, e);
+            BusError::new(e)
+        })?;
+        let options = SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(1_000_000)
+            .mode(SpiModeFlags::SPI_MODE_0)
+            .build();
+        spi.configure(&options).map_err(|e| {
+            error!("failed to configure '{}': {:?}", device, e);
+            BusError::new(e)
+        })?;
+        let sensor = BME680::new_spi(spi, HardwareChipSelect, Delay);
+        if sensor.is_ok() {
+            info!("successfully initialized '{}'", device);
         } else {
-            let e = SensorError::from(rslt);
-            trace!("error reading data: '{}'", e);
-            Err(e)
+            info!("failed to initialize '{}'", device);
         }
+        sensor
     }
+}
 
-    pub fn get_pressure_oversampling(&self) -> Oversampling {
-        Oversampling::from(self.native_device.tph_sett.os_pres)
+impl<I2C, D> BME680<I2cInterface<I2C>, D>
+where
+    I2cInterface<I2C>: Interface,
+    D: DelayMs<u32>,
+{
+    ///
+    /// Initializes the sensor at `device_id` on an I2C bus.
+    ///
+    pub fn new_i2c(i2c: I2C, device_id: Bme680Address, delay: D) -> Result<Self, SensorError> {
+        BME680::init(
+            I2cInterface::new(i2c, device_id),
+            delay,
+            bme680_intf_BME680_I2C_INTF,
+            device_id as u8,
+        )
     }
+}
 
-    pub fn get_humidity_oversampling(&self) -> Oversampling {
-        Oversampling::from(self.native_device.tph_sett.os_hum)
+impl<SPI, CS, D> BME680<SpiInterface<SPI, CS>, D>
+where
+    SpiInterface<SPI, CS>: Interface,
+    D: DelayMs<u32>,
+{
+    ///
+    /// Initializes the sensor on a SPI bus, selected by the `cs` pin.
+    ///
+    pub fn new_spi(spi: SPI, cs: CS, delay: D) -> Result<Self, SensorError> {
+        BME680::init(
+            SpiInterface::new(spi, cs),
+            delay,
+            bme680_intf_BME680_SPI_INTF,
+            0,
+        )
     }
+}
 
-    pub fn get_temperature_oversampling(&self) -> Oversampling {
-        Oversampling::from(self.native_device.tph_sett.os_temp)
+impl<I, D> BME680<I, D>
+where
+    I: Interface,
+    D: DelayMs<u32>,
+{
+    pub(crate) fn raw_init(interface: I, delay: D, dev: bme680_dev) -> BME680<I, D> {
+        BME680 {
+            transport: Transport {
+                interface,
+                delay,
+                error: None,
+            },
+            controller: Controller::new(dev),
+        }
     }
 
-    pub fn set_pressure_oversampling(&mut self, oversampling: Oversampling) {
-        self.native_device.tph_sett.os_pres = oversampling as u8;
-        self.reset = true;
+    fn init(interface: I, delay: D, intf: bme680_intf, dev_id: u8) -> Result<Self, SensorError> {
+        let mut sensor = BME680::raw_init(interface, delay, driver::new_device(intf, dev_id));
+        block_on(sensor.controller.init(&mut sensor.transport))?;
+        Ok(sensor)
     }
 
-    pub fn set_humidity_oversampling(&mut self, oversampling: Oversampling) {
-        self.native_device.tph_sett.os_hum = oversampling as u8;
-        self.reset = true;
+    ///
+    /// Gives back the bus interface and the delay provider.
+    ///
+    pub fn release(self) -> (I, D) {
+        (self.transport.interface, self.transport.delay)
     }
 
-    pub fn set_temperature_oversampling(&mut self, oversampling: Oversampling) {
-        self.native_device.tph_sett.os_temp = oversampling as u8;
-        self.reset = true;
+    ///
+    /// Runs a forced measurement and waits for its result. Gas measurements with an
+    /// unstable heater are handled according to `set_unstable_heater_policy`.
+    ///
+    pub fn read_all(&mut self) -> Result<Bme680Data, SensorError> {
+        block_on(self.controller.read_all(&mut self.transport))
     }
 
-    pub fn get_filter_size(&self) -> FilterSize {
-        FilterSize::from(self.native_device.tph_sett.filter)
+    ///
+    /// Measures and feeds gas resistance and humidity to the IAQ estimator. Measurements
+    /// without a valid gas resistance return the previous estimate. For a stable baseline,
+    /// call this at a steady rate, ideally once per second.
+    ///
+    pub fn read_iaq(&mut self) -> Result<Iaq, SensorError> {
+        block_on(self.controller.read_iaq(&mut self.transport))
     }
 
-    pub fn set_filter_size(&mut self, filter: FilterSize) {
-        self.native_device.tph_sett.filter = filter as u8;
-        self.reset = true;
+    ///
+    /// Like `read_all`, with the time the measurement finished.
+    ///
+    #[cfg(feature = "std")]
+    pub fn read_all_timestamped(&mut self) -> Result<Timestamped<Bme680Data>, SensorError> {
+        self.read_all().map(Timestamped::now)
     }
-    pub fn set_enable_gas_resistence(&mut self, enable: bool) {
-        if enable {
-            self.native_device.gas_sett.run_gas = BME680_ENABLE_GAS_MEAS;
-        } else {
-        }
+
+    ///
+    /// Reads the settings the sensor is actually configured with, see
+    /// `Bme680Config::from_registers` for the heater settings. Settings are written with
+    /// the next measurement, so until then they differ from `config`.
+    ///
+    pub fn read_config(&mut self) -> Result<Bme680Config, SensorError> {
+        block_on(self.controller.read_config(&mut self.transport))
     }
 
-    pub fn get_gas_resistence(&self) -> bool {
-        self.native_device.gas_sett.run_gas == BME680_ENABLE_GAS_MEAS
+    pub fn read_config_registers(&mut self) -> Result<ConfigRegisters, SensorError> {
+        block_on(self.controller.read_config_registers(&mut self.transport))
     }
+
+    ///
+    /// Runs one forced measurement per step of the heater profile and returns the gas
+    /// resistance measured at each of them. Gas measurements and the heater have to be
+    /// enabled and a profile has to be set with `set_heater_profile`.
+    ///
+    /// The scan uses the heater set-point registers that single measurements use too, so
+    /// the next `read_all` reconfigures the sensor.
+    ///
+    pub fn scan_heater_profile(&mut self) -> Result<GasScan, SensorError> {
+        block_on(self.controller.scan_heater_profile(&mut self.transport))
+    }
+
+    controller::settings_api!();
 }
 
-impl Thermometer for BME680 {
+impl<I, D> Thermometer for BME680<I, D>
+where
+    I: Interface,
+    D: DelayMs<u32>,
+{
     fn temperature_celsius(&mut self) -> Result<f32, SensorError> {
         self.read_all().map(|data| data.temperature)
     }
 }
 
-impl Barometer for BME680 {
+impl<I, D> Barometer for BME680<I, D>
+where
+    I: Interface,
+    D: DelayMs<u32>,
+{
     fn pressure_hpa(&mut self) -> Result<u32, SensorError> {
         self.read_all().map(|data| data.pressure)
     }
@@ -333,13 +802,26 @@ impl Barometer for BME680 {
     }
 }
 
-impl AirQualitySensor for BME680 {
+impl<I, D> AirQualitySensor for BME680<I, D>
+where
+    I: Interface,
+    D: DelayMs<u32>,
+{
     fn gas_resistance(&mut self) -> Result<Option<u32>, SensorError> {
         self.read_all().map(|data| data.gas_resistance)
     }
 
+    ///
+    /// The index of `read_iaq`. Earlier versions returned the temperature here, now gas
+    /// measurements, which are off by default, have to be enabled with
+    /// `set_enable_gas_resistence`, otherwise this fails with `InvalidConfiguration`.
+    ///
+    /// The index is meaningless while the estimator burns in, check
+    /// `iaq_estimator().last().accuracy` for the accuracy of the returned value, or use
+    /// `read_iaq` to get both.
+    ///
     fn aqi(&mut self) -> Result<f32, SensorError> {
-        self.read_all().map(|data| data.temperature)
+        self.read_iaq().map(|iaq| iaq.index)
     }
 }
 
@@ -347,38 +829,658 @@ impl AirQualitySensor for BME680 {
 mod tests {
     use super::*;
 
-    thread_local!(static DATA: RefCell<BTreeMap<u8, LinuxI2CDevice>> = RefCell::new(BTreeMap::new()));
+    pub(crate) struct FakeInterface {
+        rw_result: u8,
+    }
+
+    impl Interface for FakeInterface {
+        type Error = testing::SimulatorError;
+
+        fn read(&mut self, _reg_addr: u8, _data: &mut [u8]) -> Result<(), testing::SimulatorError> {
+            if self.rw_result == 0 {
+                Ok(())
+            } else {
+                Err(testing::SimulatorError::Nack)
+            }
+        }
+
+        fn write(&mut self, _reg_addr: u8, _data: &[u8]) -> Result<(), testing::SimulatorError> {
+            if self.rw_result == 0 {
+                Ok(())
+            } else {
+                Err(testing::SimulatorError::Nack)
+            }
+        }
+    }
+
+    pub(crate) struct NoDelay;
 
-    unsafe extern "C" fn test_write(dev_id: u8, reg_addr: u8, data: *mut u8, len: u16) -> i8 {
-        DATA.with(|data| data.borrow().get(&dev_id).map_or(1, |data| 0))
+    impl DelayMs<u32> for NoDelay {
+        fn delay_ms(&mut self, _ms: u32) {}
     }
 
-    unsafe extern "C" fn test_read(dev_id: u8, reg_addr: u8, data: *mut u8, len: u16) -> i8 {
-        DATA.with(|data| data.borrow().get(&dev_id).map_or(1, |data| 0))
+    pub(crate) fn fake_device(rw_result: u8) -> BME680<FakeInterface, NoDelay> {
+        BME680::raw_init(
+            FakeInterface { rw_result },
+            NoDelay,
+            driver::new_device(bme680_intf_BME680_I2C_INTF, BME680_I2C_ADDR_PRIMARY),
+        )
     }
 
-    unsafe extern "C" fn test_delay(ms: u32) {}
+    fn simulated_device(
+        simulator: testing::Bme680Simulator,
+    ) -> BME680<I2cInterface<testing::Bme680Simulator>, testing::NoDelay> {
+        BME680::new_i2c(simulator, Bme680Address::Primary, testing::NoDelay).unwrap()
+    }
 
-    fn fake_device(rw_result: u8) -> BME680 {
-        BME680::raw_init(bme680_dev {
-            chip_id: BME680_CHIP_ID,
-            dev_id: rw_result, // i2c address
-            intf: bme680_intf_BME680_I2C_INTF,
-            mem_page: 0,
-            amb_temp: 25, // according to specs
-            calib: bme680_calib_data::default(),
-            tph_sett: bme680_tph_sett::default(),
-            gas_sett: bme680_gas_sett::default(),
-            power_mode: BME680_SLEEP_MODE, // sleep mode, 0x01 forced mode,
-            new_fields: 0,
-            info_msg: 0,
-            read: Some(test_read),
-            write: Some(test_write),
-            delay_ms: Some(test_delay),
-            com_rslt: 0,
-        })
+    #[test]
+    fn read_environment() {
+        let mut simulator = testing::Bme680Simulator::new();
+        simulator.set_environment(testing::Environment {
+            temperature: 23.5,
+            pressure: 98_000.0,
+            humidity: 40.0,
+            gas_resistance: 80_000.0,
+        });
+        let mut sensor = simulated_device(simulator);
+        assert_eq!(sensor.calibration(), testing::typical_calibration());
+        sensor
+            .set_temperature_oversampling(Oversampling::_2X)
+            .unwrap();
+        sensor.set_pressure_oversampling(Oversampling::_4X).unwrap();
+        sensor.set_humidity_oversampling(Oversampling::_1X).unwrap();
+
+        let data = sensor.read_all().unwrap();
+        assert!((data.temperature - 23.5).abs() < 0.05);
+        assert!((data.pressure as i32 - 98_000).abs() < 10);
+        assert!((data.humidity - 40.0).abs() < 0.2);
+        assert_eq!(data.gas_resistance, None);
+    }
+
+    fn spi_device(
+        simulator: testing::Bme680Simulator,
+    ) -> BME680<SpiInterface<testing::Bme680Simulator, interface::HardwareChipSelect>, NoDelay>
+    {
+        BME680::new_spi(simulator, interface::HardwareChipSelect, NoDelay).unwrap()
+    }
+
+    #[test]
+    fn read_environment_over_spi() {
+        let mut simulator = testing::Bme680Simulator::new_spi();
+        simulator.set_environment(testing::Environment {
+            temperature: 23.5,
+            humidity: 40.0,
+            ..testing::Environment::default()
+        });
+        let mut sensor = spi_device(simulator);
+        // calibration and chip id are read from the upper memory page
+        assert_eq!(sensor.calibration(), testing::typical_calibration());
+        assert_eq!(sensor.controller.native_device.chip_id, BME680_CHIP_ID);
+        sensor
+            .set_temperature_oversampling(Oversampling::_2X)
+            .unwrap();
+        sensor.set_humidity_oversampling(Oversampling::_1X).unwrap();
+
+        let data = sensor.read_all().unwrap();
+        assert!((data.temperature - 23.5).abs() < 0.05);
+        assert!((data.humidity - 40.0).abs() < 0.2);
+        let config = sensor.read_config().unwrap();
+        assert_eq!(config.temperature_oversampling, Oversampling::_2X);
+
+        // the field data is in the lower memory page
+        let simulator = sensor.release().0.release().0;
+        assert_eq!(
+            simulator.register(BME680_MEM_PAGE_ADDR & BME680_SPI_WR_MSK) & BME680_MEM_PAGE_MSK,
+            BME680_MEM_PAGE0
+        );
+        assert_eq!(
+            simulator.register(BME680_CONF_OS_H_ADDR) & BME680_OSH_MSK,
+            Oversampling::_1X.to_register()
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "ffi"))]
+    fn spi_reads_are_split() {
+        let mut simulator = testing::Bme680Simulator::new_spi();
+        // registers the soft reset keeps
+        for reg_addr in 0x76..=0xcf_u8 {
+            simulator.set_register(reg_addr, reg_addr);
+        }
+        let mut sensor = spi_device(simulator);
+        let mut read = |reg_addr, data: &mut [u8]| {
+            driver::read_regs(
+                reg_addr,
+                data,
+                &mut sensor.controller.native_device,
+                &mut sensor.transport,
+            )
+        };
+
+        // crosses from the lower into the upper memory page
+        let mut data = [0_u8; 8];
+        assert_eq!(read(0x7c, &mut data), BME680_OK);
+        assert_eq!(data, [0x7c, 0x7d, 0x7e, 0x7f, 0x80, 0x81, 0x82, 0x83]);
+
+        // longer than the interface's transfer buffer
+        let mut data = [0_u8; 0x50];
+        assert_eq!(read(0x80, &mut data), BME680_OK);
+        assert!(data.iter().copied().eq(0x80..0xd0));
+
+        let mut data = [0_u8; 2];
+        assert_eq!(read(0xff, &mut data), BME680_E_INVALID_LENGTH);
+
+        // the interface only reads within a page
+        let mut interface = sensor.release().0;
+        let mut data = [0_u8; 8];
+        assert!(matches!(
+            interface.read(0x7c | BME680_SPI_RD_MSK, &mut data),
+            Err(interface::SpiError::PageOverflow)
+        ));
+    }
+
+    const ADC_READINGS: testing::AdcReadings = testing::AdcReadings {
+        temperature: 500_000,
+        pressure: 400_000,
+        humidity: 20_000,
+        gas: 600,
+        gas_range: 5,
+    };
+
+    #[test]
+    fn read_temperature() {
+        let mut simulator = testing::Bme680Simulator::new();
+        simulator.set_adc_readings(ADC_READINGS);
+        let mut sensor = simulated_device(simulator);
+        sensor
+            .set_temperature_oversampling(Oversampling::_1X)
+            .unwrap();
+        sensor.set_pressure_oversampling(Oversampling::_1X).unwrap();
+        sensor.set_humidity_oversampling(Oversampling::_1X).unwrap();
+
+        let calib = sensor.calibration();
+        assert_eq!(calib.par_t1, 26130);
+        assert_eq!(calib.par_h1, 771);
+        assert_eq!(calib.par_h2, 1014);
+        assert_eq!(calib.res_heat_range, 1);
+
+        let (temperature, t_fine) = compensation::calc_temperature(500_000, &calib);
+        let data = sensor.read_all().unwrap();
+        assert_eq!(data.temperature, temperature as f32 / 100.0);
+        assert_eq!(
+            data.pressure,
+            compensation::calc_pressure(400_000, t_fine, &calib)
+        );
+        assert_eq!(
+            data.humidity,
+            compensation::calc_humidity(20_000, t_fine, &calib) as f32 / 1000.0
+        );
+        assert_eq!(data.gas_resistance, None);
+    }
+
+    #[test]
+    fn gas_resistance_requires_valid_flag() {
+        let mut simulator = testing::Bme680Simulator::new();
+        simulator.set_adc_readings(ADC_READINGS);
+        let mut sensor = simulated_device(simulator);
+        sensor.set_enable_gas_resistence(true);
+
+        let calib = sensor.calibration();
+        let data = sensor.read_all().unwrap();
+        assert!(data.gas_valid);
+        assert!(data.heat_stable);
+        assert_eq!(
+            data.gas_resistance,
+            Some(compensation::calc_gas_resistance(600, 5, &calib))
+        );
+
+        let (interface, _) = sensor.release();
+        let mut simulator = interface.release();
+        simulator.set_gas_valid(false);
+        let mut sensor = simulated_device(simulator);
+        sensor.set_enable_gas_resistence(true);
+        let data = sensor.read_all().unwrap();
+        assert!(!data.gas_valid);
+        assert_eq!(data.gas_resistance, None);
+    }
+
+    #[test]
+    fn gas_valid_flag() {
+        let settings = Bme680Config {
+            gas_enabled: true,
+            ..Bme680Config::default()
+        };
+        let mut field = bme680_field_data {
+            status: BME680_NEW_DATA_MSK | BME680_GASM_VALID_MSK,
+            gas_index: 0,
+            meas_index: 0,
+            temperature: 2000,
+            pressure: 100_000,
+            humidity: 40_000,
+            gas_resistance: 50_000,
+        };
+        let data = Bme680Data::from_field_data(&field, settings);
+        assert!(data.gas_valid);
+        assert_eq!(data.gas_resistance, Some(50_000));
+
+        field.status = BME680_NEW_DATA_MSK;
+        let data = Bme680Data::from_field_data(&field, settings);
+        assert!(!data.gas_valid);
+        assert_eq!(data.gas_resistance, None);
+    }
+
+    #[test]
+    fn read_fails_without_new_data() {
+        let mut simulator = testing::Bme680Simulator::new();
+        simulator.set_data_ready(false);
+        let mut sensor = simulated_device(simulator);
+        assert!(sensor.read_all().is_err());
+    }
+
+    #[test]
+    fn settings_are_written() {
+        let mut simulator = testing::Bme680Simulator::new();
+        simulator.set_data_ready(false);
+        let mut sensor = simulated_device(simulator);
+        sensor
+            .set_temperature_oversampling(Oversampling::_2X)
+            .unwrap();
+        sensor.set_humidity_oversampling(Oversampling::_1X).unwrap();
+        sensor.set_filter_size(FilterSize::Size3);
+        sensor.set_enable_gas_resistence(true);
+        sensor.set_enable_heater(false);
+        // no new data, but the sensor is configured nonetheless
+        assert!(sensor.read_all().is_err());
+
+        let (interface, _) = sensor.release();
+        let simulator = interface.release();
+        let ctrl_meas = simulator.register(BME680_CONF_T_P_MODE_ADDR);
+        assert_eq!(ctrl_meas & BME680_OST_MSK, 2 << BME680_OST_POS);
+        assert_eq!(
+            simulator.register(BME680_CONF_OS_H_ADDR) & BME680_OSH_MSK,
+            1
+        );
+        assert_eq!(
+            simulator.register(BME680_CONF_ODR_FILT_ADDR) & BME680_FILTER_MSK,
+            2 << BME680_FILTER_POS
+        );
+        assert_eq!(
+            simulator.register(BME680_CONF_ODR_RUN_GAS_NBC_ADDR) & BME680_RUN_GAS_MSK,
+            BME680_RUN_GAS_MSK
+        );
+        assert_eq!(
+            simulator.register(BME680_CONF_HEAT_CTRL_ADDR) & BME680_HCTRL_MSK,
+            BME680_DISABLE_HEATER
+        );
+        assert_ne!(simulator.register(BME680_RES_HEAT0_ADDR), 0);
+        assert_eq!(
+            simulator.register(BME680_GAS_WAIT0_ADDR),
+            compensation::calc_heater_dur(HEATER_DURATION_DEFAULT)
+        );
+    }
+
+    #[test]
+    fn config_is_read_back() {
+        let mut sensor = simulated_device(testing::Bme680Simulator::new());
+        let config = Bme680Config::builder()
+            .temperature_oversampling(Oversampling::_2X)
+            .pressure_oversampling(Oversampling::_4X)
+            .humidity_oversampling(Oversampling::_1X)
+            .filter(FilterSize::Size7)
+            .gas(true)
+            .heater_temperature(300)
+            .heater_duration(100)
+            .build()
+            .unwrap();
+        sensor.apply_config(&config).unwrap();
+        sensor.read_all().unwrap();
+
+        let read = sensor.read_config().unwrap();
+        assert_eq!(read.temperature_oversampling, Oversampling::_2X);
+        assert_eq!(read.pressure_oversampling, Oversampling::_4X);
+        assert_eq!(read.humidity_oversampling, Oversampling::_1X);
+        assert_eq!(read.filter, FilterSize::Size7);
+        assert!(read.gas_enabled && read.heater_enabled);
+        assert!((298..=302).contains(&read.heater_temperature));
+        assert_eq!(read.heater_duration, 100);
     }
 
     #[test]
-    fn read_temperature() {}
+    fn writes_are_verified() {
+        let mut sensor = simulated_device(testing::Bme680Simulator::new());
+        sensor.set_verify_writes(true);
+        sensor
+            .set_temperature_oversampling(Oversampling::_1X)
+            .unwrap();
+        sensor.read_all().unwrap();
+
+        let mut simulator = testing::Bme680Simulator::new();
+        simulator.lock_register(BME680_CONF_OS_H_ADDR);
+        let mut sensor = simulated_device(simulator);
+        sensor
+            .set_temperature_oversampling(Oversampling::_1X)
+            .unwrap();
+        sensor.set_humidity_oversampling(Oversampling::_2X).unwrap();
+        // the sensor measures, but without humidity
+        sensor.read_all().unwrap();
+
+        sensor.set_verify_writes(true);
+        assert!(matches!(
+            sensor.read_all(),
+            Err(SensorError::VerificationFailed {
+                register: BME680_CONF_OS_H_ADDR,
+                expected: 2,
+                actual: 0,
+            })
+        ));
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for oversampling in Oversampling::ALL {
+            assert_eq!(oversampling.to_string().parse(), Ok(oversampling));
+        }
+        for filter in FilterSize::ALL {
+            assert_eq!(filter.to_string().parse(), Ok(filter));
+        }
+        for address in [Bme680Address::Primary, Bme680Address::Secondary] {
+            assert_eq!(address.to_string().parse(), Ok(address));
+        }
+        assert_eq!(Oversampling::_16X.to_string(), "16x");
+        assert_eq!(FilterSize::Size15.to_string(), "size_15");
+        assert_eq!("0x77".parse(), Ok(Bme680Address::Secondary));
+        assert_eq!(
+            "3x".parse::<Oversampling>(),
+            Err(ConfigError::Unsupported {
+                setting: "oversampling"
+            })
+        );
+        assert!("size15".parse::<FilterSize>().is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        // the serialized names are the ones of Display
+        for oversampling in Oversampling::ALL {
+            let json = serde_json::to_string(&oversampling).unwrap();
+            assert_eq!(json, format!("\"{}\"", oversampling));
+            assert_eq!(
+                serde_json::from_str::<Oversampling>(&json).unwrap(),
+                oversampling
+            );
+        }
+        for filter in FilterSize::ALL {
+            let json = serde_json::to_string(&filter).unwrap();
+            assert_eq!(json, format!("\"{}\"", filter));
+            assert_eq!(serde_json::from_str::<FilterSize>(&json).unwrap(), filter);
+        }
+        let json = serde_json::to_string(&Bme680Address::Secondary).unwrap();
+        assert_eq!(json, "\"secondary\"");
+        assert_eq!(
+            serde_json::from_str::<Bme680Address>(&json).unwrap(),
+            Bme680Address::Secondary
+        );
+
+        let mut sensor = simulated_device(testing::Bme680Simulator::new());
+        let config = config::Preset::IndoorAirQuality.config();
+        sensor.apply_config(&config).unwrap();
+        let data = sensor.read_all().unwrap();
+        let json = serde_json::to_string(&data).unwrap();
+        assert!(json.contains("\"temperature_oversampling\":\"2x\""));
+        assert!(json.contains("\"filter\":\"size_0\""));
+        assert_eq!(serde_json::from_str::<Bme680Data>(&json).unwrap(), data);
+
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(serde_json::from_str::<Bme680Config>(&json).unwrap(), config);
+    }
+
+    #[test]
+    fn heater_settings_are_validated() {
+        let mut sensor = fake_device(0);
+        assert_eq!(sensor.get_heater_temperature(), 320);
+        assert_eq!(sensor.get_heater_duration(), 150);
+        sensor.controller.reset = false;
+
+        assert!(matches!(
+            sensor.set_heater_temperature(HEATER_TEMPERATURE_MAX + 1),
+            Err(SensorError::InvalidConfiguration(ConfigError::OutOfRange {
+                setting: "heater_temperature",
+                max: 400,
+                ..
+            }))
+        ));
+        assert!(sensor.set_heater_duration(0).is_err());
+        assert!(sensor.set_heater_duration(HEATER_DURATION_MAX + 1).is_err());
+        assert!(!sensor.controller.reset);
+
+        sensor.set_heater_temperature(250).unwrap();
+        sensor.set_heater_duration(100).unwrap();
+        assert_eq!(sensor.get_heater_temperature(), 250);
+        assert_eq!(sensor.get_heater_duration(), 100);
+        assert!(sensor.controller.reset);
+    }
+
+    #[test]
+    fn oversampling_settings_are_validated() {
+        let mut sensor = fake_device(0);
+        assert_eq!(sensor.get_temperature_oversampling(), Oversampling::None);
+        sensor.controller.reset = false;
+
+        for result in [
+            sensor.set_pressure_oversampling(Oversampling::_1X),
+            sensor.set_humidity_oversampling(Oversampling::_1X),
+        ] {
+            assert!(matches!(
+                result,
+                Err(SensorError::InvalidConfiguration(ConfigError::Requires {
+                    requirement: "temperature_oversampling",
+                    ..
+                }))
+            ));
+        }
+        assert_eq!(sensor.get_pressure_oversampling(), Oversampling::None);
+        assert!(!sensor.controller.reset);
+
+        sensor
+            .set_temperature_oversampling(Oversampling::_2X)
+            .unwrap();
+        sensor.set_pressure_oversampling(Oversampling::_4X).unwrap();
+        sensor.set_humidity_oversampling(Oversampling::_1X).unwrap();
+        assert!(sensor
+            .set_temperature_oversampling(Oversampling::None)
+            .is_err());
+        assert_eq!(sensor.get_temperature_oversampling(), Oversampling::_2X);
+        // the setters accept what apply_config accepts
+        assert!(sensor.apply_config(&sensor.config()).is_ok());
+    }
+
+    #[test]
+    fn heater_profile_is_validated() {
+        let mut sensor = fake_device(0);
+        let step = HeaterStep {
+            temperature: 300,
+            duration: 100,
+        };
+        assert!(sensor.set_heater_profile(&[]).is_err());
+        assert!(sensor
+            .set_heater_profile(&[step; HEATER_PROFILE_MAX_STEPS + 1])
+            .is_err());
+        assert!(sensor
+            .set_heater_profile(&[
+                step,
+                HeaterStep {
+                    temperature: 100,
+                    duration: 100
+                }
+            ])
+            .is_err());
+        assert!(sensor.get_heater_profile().is_empty());
+
+        sensor.set_heater_profile(&[step; 3]).unwrap();
+        assert_eq!(sensor.get_heater_profile(), &[step; 3]);
+    }
+
+    #[test]
+    fn scan_needs_gas_measurements() {
+        let mut sensor = fake_device(0);
+        sensor
+            .set_heater_profile(&[HeaterStep {
+                temperature: 300,
+                duration: 100,
+            }])
+            .unwrap();
+        assert!(matches!(
+            sensor.scan_heater_profile(),
+            Err(SensorError::InvalidConfiguration(ConfigError::Requires {
+                requirement: "gas measurements",
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn aqi_needs_gas_measurements() {
+        let mut sensor = simulated_device(testing::Bme680Simulator::new());
+        assert!(matches!(
+            sensor.aqi(),
+            Err(SensorError::InvalidConfiguration(ConfigError::Requires {
+                setting: "read_iaq",
+                requirement: "gas measurements",
+            }))
+        ));
+
+        sensor.set_enable_gas_resistence(true);
+        let index = sensor.aqi().unwrap();
+        let last = sensor.iaq_estimator().last();
+        assert_eq!(last.index, index);
+        assert_eq!(last.accuracy, iaq::IaqAccuracy::Unreliable);
+    }
+
+    #[test]
+    fn gas_measurement_can_be_disabled() {
+        let mut sensor = fake_device(0);
+        sensor.set_enable_gas_resistence(true);
+        assert!(sensor.get_gas_resistence());
+        let with_gas = driver::get_profile_dur(&sensor.controller.native_device);
+
+        sensor.controller.reset = false;
+        sensor.set_enable_gas_resistence(false);
+        sensor.set_enable_heater(false);
+        assert!(!sensor.get_gas_resistence());
+        assert!(!sensor.get_heater_enabled());
+        assert!(sensor.controller.reset);
+        assert_eq!(
+            driver::get_profile_dur(&sensor.controller.native_device),
+            with_gas - sensor.get_heater_duration()
+        );
+    }
+
+    #[test]
+    fn sensor_can_be_shared_between_threads() {
+        use std::sync::{Arc, Mutex};
+
+        fn assert_send<T: Send>() {}
+        assert_send::<BME680<FakeInterface, NoDelay>>();
+
+        let sensor = Arc::new(Mutex::new(fake_device(1)));
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let sensor = Arc::clone(&sensor);
+                std::thread::spawn(move || sensor.lock().unwrap().read_all().map(|_| ()))
+            })
+            .collect();
+        for handle in handles {
+            // every thread talks to the sensor's own (failing) bus
+            assert!(matches!(
+                handle.join().unwrap(),
+                Err(SensorError::CommunicationError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn settings_in_effect() {
+        let mut sensor = fake_device(0);
+        sensor.set_filter_size(FilterSize::Size3);
+        sensor.set_enable_gas_resistence(true);
+        sensor.set_enable_heater(false);
+        sensor.set_heater_temperature(250).unwrap();
+        let settings = sensor.config();
+        assert_eq!(settings.filter, FilterSize::Size3);
+        assert!(settings.gas_enabled);
+        assert!(!settings.heater_enabled);
+        assert_eq!(settings.heater_temperature, 250);
+        assert_eq!(settings.heater_duration, HEATER_DURATION_DEFAULT);
+    }
+
+    #[test]
+    fn unstable_heater_policy() {
+        let mut settings = fake_device(0).config();
+        settings.gas_enabled = true;
+        let data = |heat_stable| Bme680Data {
+            temperature: 20.0,
+            pressure: 1000,
+            humidity: 40.0,
+            gas_resistance: Some(50_000),
+            meas_index: 0,
+            gas_index: 0,
+            heat_stable,
+            gas_valid: true,
+            settings,
+        };
+
+        let keep = UnstableHeaterPolicy::Keep;
+        assert_eq!(keep.apply(data(false)).gas_resistance, Some(50_000));
+        assert!(!keep.needs_retry(&data(false)));
+
+        let discard = UnstableHeaterPolicy::Discard;
+        assert_eq!(discard.apply(data(false)).gas_resistance, None);
+        assert_eq!(discard.apply(data(true)).gas_resistance, Some(50_000));
+
+        let retry = UnstableHeaterPolicy::Retry(2);
+        assert!(retry.needs_retry(&data(false)));
+        assert!(!retry.needs_retry(&data(true)));
+        assert_eq!(retry.apply(data(false)).gas_resistance, None);
+    }
+
+    #[test]
+    fn read_fails_on_bus_error() {
+        let mut sensor = fake_device(1);
+        assert!(matches!(
+            sensor.read_all(),
+            Err(SensorError::CommunicationError(_))
+        ));
+
+        let e = match sensor.read_all() {
+            Err(SensorError::CommunicationError(e)) => e,
+            other => panic!("unexpected result {:?}", other),
+        };
+        assert!(e.operation().is_some());
+        assert!(e.register().is_some());
+        #[cfg(feature = "std")]
+        {
+            use std::error::Error;
+            assert_eq!(
+                e.source().unwrap().downcast_ref(),
+                Some(&testing::SimulatorError::Nack)
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "linux")]
+    fn open_error_is_kept() {
+        use std::error::Error;
+
+        for result in [
+            BME680::initialize("/dev/bme680-missing", Bme680Address::Primary).map(|_| ()),
+            BME680::initialize_spi("/dev/bme680-missing").map(|_| ()),
+        ] {
+            match result {
+                Err(e @ SensorError::CommunicationError(_)) => assert!(e.source().is_some()),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
 }
@@ -0,0 +1,578 @@
+use crate::compensation::{
+    calc_gas_resistance, calc_heater_dur, calc_heater_res, calc_humidity, calc_pressure,
+    calc_temperature, CalibrationData,
+};
+use crate::controller::block_on;
+use crate::source::*;
+use crate::Bus;
+
+use log::debug;
+
+const OS_TO_MEAS_CYCLES: [u32; 6] = [0, 1, 2, 4, 8, 16];
+
+pub(crate) type Result = core::result::Result<(), i8>;
+
+///
+/// Register access of the driver functions below. Blocking buses are wrapped in futures that
+/// are ready right away, so the blocking and the async sensor share the same driver.
+///
+pub(crate) trait AsyncBus {
+    async fn read(&mut self, reg_addr: u8, data: &mut [u8]) -> i8;
+    async fn write(&mut self, reg_addr: u8, data: &[u8]) -> i8;
+    async fn delay_ms(&mut self, period: u32);
+}
+
+impl AsyncBus for dyn Bus + '_ {
+    async fn read(&mut self, reg_addr: u8, data: &mut [u8]) -> i8 {
+        Bus::read(self, reg_addr, data)
+    }
+
+    async fn write(&mut self, reg_addr: u8, data: &[u8]) -> i8 {
+        Bus::write(self, reg_addr, data)
+    }
+
+    async fn delay_ms(&mut self, period: u32) {
+        Bus::delay_ms(self, period)
+    }
+}
+
+pub(crate) fn code(result: Result) -> i8 {
+    match result {
+        Ok(()) => BME680_OK,
+        Err(e) => e,
+    }
+}
+
+fn set_bits(reg_data: u8, mask: u8, pos: u8, data: u8) -> u8 {
+    (reg_data & !mask) | ((data << pos) & mask)
+}
+
+fn set_bits_pos_0(reg_data: u8, mask: u8, data: u8) -> u8 {
+    (reg_data & !mask) | (data & mask)
+}
+
+pub(crate) fn calibration(dev: &bme680_dev) -> CalibrationData {
+    dev.calib
+}
+
+pub(crate) fn new_device(intf: bme680_intf, dev_id: u8) -> bme680_dev {
+    bme680_dev {
+        chip_id: BME680_CHIP_ID,
+        dev_id, // i2c address, unused for SPI
+        intf,
+        mem_page: 0,
+        amb_temp: 25, // according to specs
+        calib: CalibrationData::default(),
+        tph_sett: bme680_tph_sett::default(),
+        gas_sett: bme680_gas_sett::default(),
+        power_mode: BME680_SLEEP_MODE, // sleep mode, 0x01 forced mode,
+        new_fields: 0,
+        info_msg: 0,
+    }
+}
+
+///
+/// Soft-resets the sensor, checks its chip id and reads the calibration data.
+///
+pub(crate) fn init(dev: &mut bme680_dev, bus: &mut dyn Bus) -> i8 {
+    code(block_on(init_device(dev, bus)))
+}
+
+pub(crate) async fn init_device<B: AsyncBus + ?Sized>(dev: &mut bme680_dev, bus: &mut B) -> Result {
+    soft_reset(dev, bus).await?;
+    let mut chip_id = [0_u8];
+    get_regs(BME680_CHIP_ID_ADDR, &mut chip_id, dev, bus).await?;
+    dev.chip_id = chip_id[0];
+    if dev.chip_id == BME680_CHIP_ID {
+        get_calib_data(dev, bus).await
+    } else {
+        debug!("unexpected chip id 0x{:x}", dev.chip_id);
+        Err(BME680_E_DEV_NOT_FOUND)
+    }
+}
+
+async fn soft_reset<B: AsyncBus + ?Sized>(dev: &mut bme680_dev, bus: &mut B) -> Result {
+    if dev.intf == bme680_intf_BME680_SPI_INTF {
+        get_mem_page(dev, bus).await?;
+    }
+    let rslt = set_regs(
+        &[BME680_SOFT_RESET_ADDR],
+        &[BME680_SOFT_RESET_CMD],
+        dev,
+        bus,
+    )
+    .await;
+    bus.delay_ms(BME680_RESET_PERIOD).await;
+    rslt?;
+    if dev.intf == bme680_intf_BME680_SPI_INTF {
+        get_mem_page(dev, bus).await?;
+    }
+    Ok(())
+}
+
+async fn get_calib_data<B: AsyncBus + ?Sized>(dev: &mut bme680_dev, bus: &mut B) -> Result {
+    let mut coeff_array = [0_u8; BME680_COEFF_SIZE as usize];
+    let (coeff1, coeff2) = coeff_array.split_at_mut(BME680_COEFF_ADDR1_LEN as usize);
+    get_regs(BME680_COEFF_ADDR1, coeff1, dev, bus).await?;
+    get_regs(BME680_COEFF_ADDR2, coeff2, dev, bus).await?;
+
+    let mut res_heat_range = [0_u8];
+    get_regs(
+        BME680_ADDR_RES_HEAT_RANGE_ADDR,
+        &mut res_heat_range,
+        dev,
+        bus,
+    )
+    .await?;
+    let mut res_heat_val = [0_u8];
+    get_regs(BME680_ADDR_RES_HEAT_VAL_ADDR, &mut res_heat_val, dev, bus).await?;
+    let mut range_sw_err = [0_u8];
+    get_regs(BME680_ADDR_RANGE_SW_ERR_ADDR, &mut range_sw_err, dev, bus).await?;
+
+    dev.calib = CalibrationData::from_registers(
+        &coeff_array,
+        res_heat_range[0],
+        res_heat_val[0],
+        range_sw_err[0],
+    );
+    Ok(())
+}
+
+///
+/// Writes the settings selected by `desired_settings` to the sensor.
+///
+pub(crate) fn set_sensor_settings(
+    desired_settings: u16,
+    dev: &mut bme680_dev,
+    bus: &mut dyn Bus,
+) -> i8 {
+    code(block_on(write_settings(desired_settings, dev, bus)))
+}
+
+pub(crate) async fn write_settings<B: AsyncBus + ?Sized>(
+    desired_settings: u16,
+    dev: &mut bme680_dev,
+    bus: &mut B,
+) -> Result {
+    /* Save intended power mode */
+    let intended_power_mode = dev.power_mode;
+    let rslt = write_sensor_settings(desired_settings, dev, bus).await;
+    /* Restore previous intended power mode */
+    dev.power_mode = intended_power_mode;
+    rslt
+}
+
+async fn write_sensor_settings<B: AsyncBus + ?Sized>(
+    desired_settings: u16,
+    dev: &mut bme680_dev,
+    bus: &mut B,
+) -> Result {
+    let mut reg_array = [0_u8; BME680_REG_BUFFER_LENGTH as usize];
+    let mut data_array = [0_u8; BME680_REG_BUFFER_LENGTH as usize];
+    let mut count = 0;
+    let mut data = [0_u8];
+
+    let gas_config = if desired_settings & BME680_GAS_MEAS_SEL != 0 {
+        set_gas_config(dev, bus).await
+    } else {
+        Ok(())
+    };
+
+    dev.power_mode = BME680_SLEEP_MODE;
+    match gas_config {
+        Ok(()) => change_mode(dev, bus).await?,
+        // like the C driver, carry on with the remaining settings
+        Err(warning) if warning > 0 => debug!("heater not configured, code {}", warning),
+        Err(e) => return Err(e),
+    }
+
+    /* Selecting the filter */
+    if desired_settings & BME680_FILTER_SEL != 0 {
+        dev.tph_sett.filter = boundary_check(
+            dev.tph_sett.filter,
+            BME680_FILTER_SIZE_0,
+            BME680_FILTER_SIZE_127,
+            dev,
+        );
+        get_regs(BME680_CONF_ODR_FILT_ADDR, &mut data, dev, bus).await?;
+        reg_array[count] = BME680_CONF_ODR_FILT_ADDR;
+        data_array[count] = set_bits(
+            data[0],
+            BME680_FILTER_MSK,
+            BME680_FILTER_POS,
+            dev.tph_sett.filter,
+        );
+        count += 1;
+    }
+
+    /* Selecting heater control for the sensor */
+    if desired_settings & BME680_HCNTRL_SEL != 0 {
+        dev.gas_sett.heatr_ctrl = boundary_check(
+            dev.gas_sett.heatr_ctrl,
+            BME680_ENABLE_HEATER,
+            BME680_DISABLE_HEATER,
+            dev,
+        );
+        get_regs(BME680_CONF_HEAT_CTRL_ADDR, &mut data, dev, bus).await?;
+        reg_array[count] = BME680_CONF_HEAT_CTRL_ADDR;
+        data_array[count] = set_bits_pos_0(data[0], BME680_HCTRL_MSK, dev.gas_sett.heatr_ctrl);
+        count += 1;
+    }
+
+    /* Selecting heater T,P oversampling for the sensor */
+    if desired_settings & (BME680_OST_SEL | BME680_OSP_SEL) != 0 {
+        dev.tph_sett.os_temp =
+            boundary_check(dev.tph_sett.os_temp, BME680_OS_NONE, BME680_OS_16X, dev);
+        dev.tph_sett.os_pres =
+            boundary_check(dev.tph_sett.os_pres, BME680_OS_NONE, BME680_OS_16X, dev);
+        get_regs(BME680_CONF_T_P_MODE_ADDR, &mut data, dev, bus).await?;
+        let mut reg = data[0];
+        if desired_settings & BME680_OST_SEL != 0 {
+            reg = set_bits(reg, BME680_OST_MSK, BME680_OST_POS, dev.tph_sett.os_temp);
+        }
+        if desired_settings & BME680_OSP_SEL != 0 {
+            reg = set_bits(reg, BME680_OSP_MSK, BME680_OSP_POS, dev.tph_sett.os_pres);
+        }
+        reg_array[count] = BME680_CONF_T_P_MODE_ADDR;
+        data_array[count] = reg;
+        count += 1;
+    }
+
+    /* Selecting humidity oversampling for the sensor */
+    if desired_settings & BME680_OSH_SEL != 0 {
+        dev.tph_sett.os_hum =
+            boundary_check(dev.tph_sett.os_hum, BME680_OS_NONE, BME680_OS_16X, dev);
+        get_regs(BME680_CONF_OS_H_ADDR, &mut data, dev, bus).await?;
+        reg_array[count] = BME680_CONF_OS_H_ADDR;
+        data_array[count] = set_bits_pos_0(data[0], BME680_OSH_MSK, dev.tph_sett.os_hum);
+        count += 1;
+    }
+
+    /* Selecting the runGas and NB conversion settings for the sensor */
+    if desired_settings & (BME680_RUN_GAS_SEL | BME680_NBCONV_SEL) != 0 {
+        dev.gas_sett.run_gas = boundary_check(
+            dev.gas_sett.run_gas,
+            BME680_RUN_GAS_DISABLE,
+            BME680_RUN_GAS_ENABLE,
+            dev,
+        );
+        dev.gas_sett.nb_conv = boundary_check(
+            dev.gas_sett.nb_conv,
+            BME680_NBCONV_MIN,
+            BME680_NBCONV_MAX,
+            dev,
+        );
+        get_regs(BME680_CONF_ODR_RUN_GAS_NBC_ADDR, &mut data, dev, bus).await?;
+        let mut reg = data[0];
+        if desired_settings & BME680_RUN_GAS_SEL != 0 {
+            reg = set_bits(
+                reg,
+                BME680_RUN_GAS_MSK,
+                BME680_RUN_GAS_POS,
+                dev.gas_sett.run_gas,
+            );
+        }
+        if desired_settings & BME680_NBCONV_SEL != 0 {
+            reg = set_bits_pos_0(reg, BME680_NBCONV_MSK, dev.gas_sett.nb_conv);
+        }
+        reg_array[count] = BME680_CONF_ODR_RUN_GAS_NBC_ADDR;
+        data_array[count] = reg;
+        count += 1;
+    }
+
+    if count > 0 {
+        set_regs(&reg_array[..count], &data_array[..count], dev, bus).await?;
+    }
+    Ok(())
+}
+
+fn boundary_check(value: u8, min: u8, max: u8, dev: &mut bme680_dev) -> u8 {
+    if value < min {
+        dev.info_msg |= BME680_I_MIN_CORRECTION;
+        min
+    } else if value > max {
+        dev.info_msg |= BME680_I_MAX_CORRECTION;
+        max
+    } else {
+        value
+    }
+}
+
+async fn set_gas_config<B: AsyncBus + ?Sized>(dev: &mut bme680_dev, bus: &mut B) -> Result {
+    if dev.power_mode != BME680_FORCED_MODE {
+        return Err(BME680_W_DEFINE_PWR_MODE);
+    }
+    let reg_addr = [BME680_RES_HEAT0_ADDR, BME680_GAS_WAIT0_ADDR];
+    let reg_data = [
+        calc_heater_res(dev.gas_sett.heatr_temp, dev.amb_temp, &dev.calib),
+        calc_heater_dur(dev.gas_sett.heatr_dur),
+    ];
+    dev.gas_sett.nb_conv = 0;
+    set_regs(&reg_addr, &reg_data, dev, bus).await
+}
+
+///
+/// Puts the sensor to sleep and then into `dev.power_mode`.
+///
+pub(crate) fn set_sensor_mode(dev: &mut bme680_dev, bus: &mut dyn Bus) -> i8 {
+    code(block_on(change_mode(dev, bus)))
+}
+
+pub(crate) async fn change_mode<B: AsyncBus + ?Sized>(dev: &mut bme680_dev, bus: &mut B) -> Result {
+    let mut tmp_pow_mode = [0_u8];
+    /* Call repeatedly until in sleep */
+    loop {
+        get_regs(BME680_CONF_T_P_MODE_ADDR, &mut tmp_pow_mode, dev, bus).await?;
+        /* Put to sleep before changing mode */
+        if tmp_pow_mode[0] & BME680_MODE_MSK == BME680_SLEEP_MODE {
+            break;
+        }
+        tmp_pow_mode[0] &= !BME680_MODE_MSK; /* Set to sleep */
+        set_regs(&[BME680_CONF_T_P_MODE_ADDR], &tmp_pow_mode, dev, bus).await?;
+        bus.delay_ms(BME680_POLL_PERIOD_MS as u32).await;
+    }
+
+    /* Already in sleep */
+    if dev.power_mode != BME680_SLEEP_MODE {
+        tmp_pow_mode[0] = (tmp_pow_mode[0] & !BME680_MODE_MSK) | (dev.power_mode & BME680_MODE_MSK);
+        set_regs(&[BME680_CONF_T_P_MODE_ADDR], &tmp_pow_mode, dev, bus).await?;
+    }
+    Ok(())
+}
+
+///
+/// Duration of a measurement with the current settings in milliseconds.
+///
+pub(crate) fn get_profile_dur(dev: &bme680_dev) -> u16 {
+    let meas_cycles = OS_TO_MEAS_CYCLES[dev.tph_sett.os_temp as usize]
+        + OS_TO_MEAS_CYCLES[dev.tph_sett.os_pres as usize]
+        + OS_TO_MEAS_CYCLES[dev.tph_sett.os_hum as usize];
+
+    /* TPH measurement duration, in us */
+    let mut tph_dur = meas_cycles * 1963;
+    tph_dur += 477 * 4; /* TPH switching duration */
+    tph_dur += 477 * 5; /* Gas measurement duration */
+    tph_dur += 500; /* Get it to the closest whole number.*/
+    tph_dur /= 1000; /* Convert to ms */
+    tph_dur += 1; /* Wake up duration of 1ms */
+
+    let mut duration = tph_dur as u16;
+    /* Get the gas duration only when the run gas is enabled */
+    if dev.gas_sett.run_gas != 0 {
+        /* The remaining time should be used for heating */
+        duration += dev.gas_sett.heatr_dur;
+    }
+    duration
+}
+
+///
+/// Reads and compensates the sensor's field data.
+///
+pub(crate) fn get_sensor_data(
+    data: &mut bme680_field_data,
+    dev: &mut bme680_dev,
+    bus: &mut dyn Bus,
+) -> i8 {
+    code(block_on(read_sensor_data(data, dev, bus)))
+}
+
+pub(crate) async fn read_sensor_data<B: AsyncBus + ?Sized>(
+    data: &mut bme680_field_data,
+    dev: &mut bme680_dev,
+    bus: &mut B,
+) -> Result {
+    let rslt = read_field_data(data, dev, bus).await;
+    if rslt.is_ok() {
+        dev.new_fields = if data.status & BME680_NEW_DATA_MSK != 0 {
+            1
+        } else {
+            0
+        };
+    }
+    rslt
+}
+
+///
+/// Decodes and compensates the field data registers starting at `BME680_FIELD0_ADDR`.
+/// Returns whether they hold new data.
+///
+fn parse_field_data(
+    buff: &[u8; BME680_FIELD_LENGTH as usize],
+    data: &mut bme680_field_data,
+    calib: &CalibrationData,
+) -> bool {
+    data.status = buff[0] & BME680_NEW_DATA_MSK;
+    data.gas_index = buff[0] & BME680_GAS_INDEX_MSK;
+    data.meas_index = buff[1];
+
+    /* read the raw data from the sensor */
+    let adc_pres = (buff[2] as u32) << 12 | (buff[3] as u32) << 4 | (buff[4] as u32) >> 4;
+    let adc_temp = (buff[5] as u32) << 12 | (buff[6] as u32) << 4 | (buff[7] as u32) >> 4;
+    let adc_hum = (buff[8] as u16) << 8 | buff[9] as u16;
+    let adc_gas_res = (buff[13] as u16) << 2 | (buff[14] as u16) >> 6;
+    let gas_range = buff[14] & BME680_GAS_RANGE_MSK;
+
+    data.status |= buff[14] & BME680_GASM_VALID_MSK;
+    data.status |= buff[14] & BME680_HEAT_STAB_MSK;
+
+    if data.status & BME680_NEW_DATA_MSK == 0 {
+        return false;
+    }
+    let (temperature, t_fine) = calc_temperature(adc_temp, calib);
+    data.temperature = temperature;
+    data.pressure = calc_pressure(adc_pres, t_fine, calib);
+    data.humidity = calc_humidity(adc_hum, t_fine, calib);
+    data.gas_resistance = calc_gas_resistance(adc_gas_res, gas_range, calib);
+    true
+}
+
+async fn read_field_data<B: AsyncBus + ?Sized>(
+    data: &mut bme680_field_data,
+    dev: &mut bme680_dev,
+    bus: &mut B,
+) -> Result {
+    let mut buff = [0_u8; BME680_FIELD_LENGTH as usize];
+    for _ in 0..10 {
+        get_regs(BME680_FIELD0_ADDR, &mut buff, dev, bus).await?;
+        if parse_field_data(&buff, data, &dev.calib) {
+            return Ok(());
+        }
+        /* Delay to poll the data */
+        bus.delay_ms(BME680_POLL_PERIOD_MS as u32).await;
+    }
+    Err(BME680_W_NO_NEW_DATA)
+}
+
+///
+/// Writes `reg_data` to the registers `reg_addr`, one value per register.
+///
+pub(crate) fn write_regs(
+    reg_addr: &[u8],
+    reg_data: &[u8],
+    dev: &mut bme680_dev,
+    bus: &mut dyn Bus,
+) -> i8 {
+    code(block_on(set_regs(reg_addr, reg_data, dev, bus)))
+}
+
+///
+/// Reads `reg_data.len()` consecutive registers, starting at `reg_addr`.
+///
+pub(crate) fn read_regs(
+    reg_addr: u8,
+    reg_data: &mut [u8],
+    dev: &mut bme680_dev,
+    bus: &mut dyn Bus,
+) -> i8 {
+    code(block_on(get_regs(reg_addr, reg_data, dev, bus)))
+}
+
+pub(crate) async fn get_regs<B: AsyncBus + ?Sized>(
+    reg_addr: u8,
+    reg_data: &mut [u8],
+    dev: &mut bme680_dev,
+    bus: &mut B,
+) -> Result {
+    if dev.intf != bme680_intf_BME680_SPI_INTF {
+        if bus.read(reg_addr, reg_data).await != 0 {
+            return Err(BME680_E_COM_FAIL);
+        }
+        return Ok(());
+    }
+    if reg_addr as usize + reg_data.len() > 0x100 {
+        return Err(BME680_E_INVALID_LENGTH);
+    }
+    /* Reads are split where they cross into the other memory page */
+    let mut reg_addr = reg_addr as usize;
+    let mut reg_data = reg_data;
+    while !reg_data.is_empty() {
+        let page_end = (reg_addr | 0x7f) + 1;
+        let len = reg_data.len().min(page_end - reg_addr);
+        let (page_data, rest) = reg_data.split_at_mut(len);
+        set_mem_page(reg_addr as u8, dev, bus).await?;
+        if bus
+            .read(reg_addr as u8 | BME680_SPI_RD_MSK, page_data)
+            .await
+            != 0
+        {
+            return Err(BME680_E_COM_FAIL);
+        }
+        reg_addr = page_end;
+        reg_data = rest;
+    }
+    Ok(())
+}
+
+pub(crate) async fn set_regs<B: AsyncBus + ?Sized>(
+    reg_addr: &[u8],
+    reg_data: &[u8],
+    dev: &mut bme680_dev,
+    bus: &mut B,
+) -> Result {
+    let len = reg_addr.len();
+    if len == 0 || len >= BME680_TMP_BUFFER_LENGTH as usize / 2 || len != reg_data.len() {
+        return Err(BME680_E_INVALID_LENGTH);
+    }
+    /* Interleave the 2 arrays */
+    let mut tmp_buff = [0_u8; BME680_TMP_BUFFER_LENGTH as usize];
+    for (index, (&addr, &data)) in reg_addr.iter().zip(reg_data).enumerate() {
+        tmp_buff[2 * index] = if dev.intf == bme680_intf_BME680_SPI_INTF {
+            /* Set the memory page */
+            set_mem_page(addr, dev, bus).await?;
+            addr & BME680_SPI_WR_MSK
+        } else {
+            addr
+        };
+        tmp_buff[2 * index + 1] = data;
+    }
+    /* Write the interleaved array */
+    if bus.write(tmp_buff[0], &tmp_buff[1..2 * len]).await != 0 {
+        return Err(BME680_E_COM_FAIL);
+    }
+    Ok(())
+}
+
+async fn set_mem_page<B: AsyncBus + ?Sized>(
+    reg_addr: u8,
+    dev: &mut bme680_dev,
+    bus: &mut B,
+) -> Result {
+    let mem_page = if reg_addr > 0x7f {
+        BME680_MEM_PAGE1
+    } else {
+        BME680_MEM_PAGE0
+    };
+    if mem_page != dev.mem_page {
+        dev.mem_page = mem_page;
+        let mut reg = [0_u8];
+        if bus
+            .read(BME680_MEM_PAGE_ADDR | BME680_SPI_RD_MSK, &mut reg)
+            .await
+            != 0
+        {
+            return Err(BME680_E_COM_FAIL);
+        }
+        reg[0] = (reg[0] & !BME680_MEM_PAGE_MSK) | (dev.mem_page & BME680_MEM_PAGE_MSK);
+        if bus
+            .write(BME680_MEM_PAGE_ADDR & BME680_SPI_WR_MSK, &reg)
+            .await
+            != 0
+        {
+            return Err(BME680_E_COM_FAIL);
+        }
+    }
+    Ok(())
+}
+
+async fn get_mem_page<B: AsyncBus + ?Sized>(dev: &mut bme680_dev, bus: &mut B) -> Result {
+    let mut reg = [0_u8];
+    if bus
+        .read(BME680_MEM_PAGE_ADDR | BME680_SPI_RD_MSK, &mut reg)
+        .await
+        != 0
+    {
+        return Err(BME680_E_COM_FAIL);
+    }
+    dev.mem_page = reg[0] & BME680_MEM_PAGE_MSK;
+    Ok(())
+}
@@ -0,0 +1,127 @@
+//!
+//! Continuous sampling at a fixed interval.
+//!
+
+use crate::errors::SensorError;
+use crate::interface::Interface;
+use crate::{Bme680Data, BME680};
+
+use embedded_hal::blocking::delay::DelayMs;
+use std::time::{Duration, Instant};
+
+///
+/// Keeps samples on a fixed grid of deadlines, so the time a measurement takes doesn't add up
+///
+#[derive(Debug, Clone)]
+pub(crate) struct Cadence {
+    interval: Duration,
+    next: Option<Instant>,
+}
+
+impl Cadence {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Cadence {
+            interval,
+            next: None,
+        }
+    }
+
+    ///
+    /// Time to wait at `now` before taking the next sample. If sampling fell behind by more
+    /// than an interval, the missed samples are skipped instead of taken in a burst.
+    ///
+    pub(crate) fn wait(&mut self, now: Instant) -> Duration {
+        let deadline = match self.next {
+            Some(deadline) => deadline,
+            None => now,
+        };
+        let mut next = deadline + self.interval;
+        if next <= now {
+            next = now + self.interval;
+        }
+        self.next = Some(next);
+        deadline.saturating_duration_since(now)
+    }
+}
+
+///
+/// Endless iterator over measurements, see `BME680::samples`
+///
+pub struct Samples<'a, I, D> {
+    sensor: &'a mut BME680<I, D>,
+    cadence: Cadence,
+}
+
+impl<I, D> Iterator for Samples<'_, I, D>
+where
+    I: Interface,
+    D: DelayMs<u32>,
+{
+    type Item = Result<Bme680Data, SensorError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let wait = self.cadence.wait(Instant::now());
+        if !wait.is_zero() {
+            self.sensor
+                .transport
+                .delay
+                .delay_ms(wait.as_millis() as u32);
+        }
+        Some(self.sensor.read_all())
+    }
+}
+
+impl<I, D> BME680<I, D>
+where
+    I: Interface,
+    D: DelayMs<u32>,
+{
+    ///
+    /// Measures every `interval`, starting right away. Failed measurements are returned as
+    /// errors without ending the iteration. If a measurement takes longer than `interval`,
+    /// the next one starts immediately.
+    ///
+    pub fn samples(&mut self, interval: Duration) -> Samples<'_, I, D> {
+        Samples {
+            sensor: self,
+            cadence: Cadence::new(interval),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::fake_device;
+
+    #[test]
+    fn cadence_does_not_drift() {
+        let interval = Duration::from_millis(1000);
+        let start = Instant::now();
+        let mut cadence = Cadence::new(interval);
+        assert_eq!(cadence.wait(start), Duration::ZERO);
+        // the first measurement took 300 ms
+        let now = start + Duration::from_millis(300);
+        assert_eq!(cadence.wait(now), Duration::from_millis(700));
+        // woke up late
+        let now = start + Duration::from_millis(1050);
+        assert_eq!(cadence.wait(now), Duration::from_millis(950));
+        // fell behind by more than an interval
+        let now = start + Duration::from_millis(4500);
+        assert_eq!(cadence.wait(now), Duration::ZERO);
+        assert_eq!(
+            cadence.wait(start + Duration::from_millis(4600)),
+            Duration::from_millis(900)
+        );
+    }
+
+    #[test]
+    fn errors_do_not_end_sampling() {
+        let mut sensor = fake_device(1);
+        let samples: Vec<_> = sensor.samples(Duration::from_millis(1)).take(3).collect();
+        assert_eq!(samples.len(), 3);
+        assert!(samples
+            .iter()
+            .all(|sample| matches!(sample, Err(SensorError::CommunicationError(_)))));
+    }
+}
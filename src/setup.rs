@@ -0,0 +1,406 @@
+//!
+//! Sensor setups read from configuration files, e.g. one file per site.
+//!
+//! `SensorSetup` deserializes from any serde format, like TOML or JSON. Settings not given
+//! in the file are taken from `preset` if present, else from `Bme680Config::default()`.
+//! The setup is validated while it is read, errors name the offending key, also for values
+//! that don't parse:
+//!
+//! ```
+//! use bme680::setup::SensorSetup;
+//! use bme680::Bme680Address;
+//!
+//! let setup: SensorSetup = serde_json::from_str(r#"{
+//!     "device": "/dev/i2c-1",
+//!     "address": "secondary",
+//!     "preset": "indoor_air_quality",
+//!     "heater_temperature": 300,
+//!     "sampling_interval_ms": 3000
+//! }"#).unwrap();
+//! assert_eq!(setup.address, Bme680Address::Secondary);
+//! assert_eq!(setup.config.heater_temperature, 300);
+//!
+//! let error = serde_json::from_str::<SensorSetup>(r#"{
+//!     "device": "/dev/i2c-1",
+//!     "heater_profile": [{ "temperature": 500, "duration": 100 }]
+//! }"#).unwrap_err();
+//! assert!(error.to_string().starts_with("heater_profile[0].temperature: "));
+//!
+//! let error = serde_json::from_str::<SensorSetup>(r#"{
+//!     "device": "/dev/i2c-1",
+//!     "filter": "size_2"
+//! }"#).unwrap_err();
+//! assert!(error.to_string().starts_with("filter: "));
+//! ```
+//!
+//! The same setup as TOML:
+//!
+//! ```
+//! # use bme680::setup::SensorSetup;
+//! let setup: SensorSetup = toml::from_str(r#"
+//!     device = "/dev/i2c-1"
+//!     address = "secondary"
+//!     preset = "indoor_air_quality"
+//!     heater_temperature = 300
+//!     sampling_interval_ms = 3000
+//! "#).unwrap();
+//! assert_eq!(setup.config.heater_temperature, 300);
+//! ```
+//!
+//! Besides `preset`, the keys are the fields of `SensorSetup` and `Bme680Config`, values
+//! are written as documented on `Oversampling`, `FilterSize` and `Bme680Address`. Unknown
+//! keys are rejected.
+//!
+
+use crate::config::{Bme680Config, Preset};
+use crate::errors::{ConfigError, SensorError};
+use crate::interface::Interface;
+use crate::{
+    check_heater_duration, check_heater_temperature, check_range, Bme680Address, FilterSize,
+    HeaterStep, Oversampling, BME680, HEATER_PROFILE_MAX_STEPS,
+};
+
+use core::convert::TryFrom;
+use embedded_hal::blocking::delay::DelayMs;
+use std::fmt;
+use std::time::Duration;
+
+///
+/// Bus the sensor is attached to
+///
+#[derive(Debug, Copy, Clone, PartialEq, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BusType {
+    #[default]
+    I2c,
+    Spi,
+}
+
+///
+/// Where a sensor is attached, how it measures and how often
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorSetup {
+    /// Path of the bus, e.g. `/dev/i2c-1` or `/dev/spidev0.0`
+    pub device: String,
+    pub bus: BusType,
+    /// I2C address, unused for SPI
+    pub address: Bme680Address,
+    pub config: Bme680Config,
+    /// Heater profile for `BME680::scan_heater_profile`, empty if not used
+    pub heater_profile: Vec<HeaterStep>,
+    pub sampling_interval: Option<Duration>,
+}
+
+impl SensorSetup {
+    ///
+    /// Configures `sensor` with this setup's settings and heater profile. Opening the
+    /// sensor is up to the caller, e.g. with `BME680::initialize(&setup.device, setup.address)`.
+    ///
+    pub fn apply_to<I, D>(&self, sensor: &mut BME680<I, D>) -> Result<(), SensorError>
+    where
+        I: Interface,
+        D: DelayMs<u32>,
+    {
+        sensor.apply_config(&self.config)?;
+        if !self.heater_profile.is_empty() {
+            sensor.set_heater_profile(&self.heater_profile)?;
+        }
+        Ok(())
+    }
+}
+
+///
+/// A setting of a `SensorSetup` that doesn't work, with the key it was read from
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetupError {
+    /// Path of the key, e.g. `heater_profile[1].temperature`
+    pub key: String,
+    pub error: ConfigError,
+}
+
+impl SetupError {
+    fn new(key: impl Into<String>, error: ConfigError) -> Self {
+        SetupError {
+            key: key.into(),
+            error,
+        }
+    }
+}
+
+impl fmt::Display for SetupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.error)
+    }
+}
+
+impl std::error::Error for SetupError {}
+
+impl From<SetupError> for SensorError {
+    fn from(error: SetupError) -> Self {
+        SensorError::InvalidConfiguration(error.error)
+    }
+}
+
+///
+/// Keeps the `ConfigError` of a failed check, keyed by the setting it names
+///
+fn keyed(result: Result<(), ConfigError>) -> Result<(), SetupError> {
+    result.map_err(|error| SetupError::new(error.setting(), error))
+}
+
+///
+/// Keeps the `ConfigError` of a failed check of the value at `key`
+///
+fn keyed_as(result: Result<(), ConfigError>, key: String) -> Result<(), SetupError> {
+    result.map_err(|error| SetupError::new(key, error))
+}
+
+impl<'de> serde::Deserialize<'de> for SensorSetup {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        // values that don't parse are reported with their key, like the invalid settings
+        let file: SetupFile = serde_path_to_error::deserialize(deserializer).map_err(|e| {
+            let key = e.path().to_string();
+            D::Error::custom(format_args!("{}: {}", key, e.into_inner()))
+        })?;
+        SensorSetup::try_from(file).map_err(D::Error::custom)
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SetupFile {
+    device: String,
+    #[serde(default)]
+    bus: BusType,
+    address: Option<Bme680Address>,
+    preset: Option<Preset>,
+    temperature_oversampling: Option<Oversampling>,
+    pressure_oversampling: Option<Oversampling>,
+    humidity_oversampling: Option<Oversampling>,
+    filter: Option<FilterSize>,
+    gas_enabled: Option<bool>,
+    heater_enabled: Option<bool>,
+    heater_temperature: Option<u16>,
+    heater_duration: Option<u16>,
+    #[serde(default)]
+    heater_profile: Vec<HeaterStep>,
+    sampling_interval_ms: Option<u32>,
+}
+
+impl TryFrom<SetupFile> for SensorSetup {
+    type Error = SetupError;
+
+    fn try_from(file: SetupFile) -> Result<Self, SetupError> {
+        if file.bus == BusType::Spi && file.address.is_some() {
+            return Err(SetupError::new(
+                "address",
+                ConfigError::Requires {
+                    setting: "address",
+                    requirement: "an I2C bus",
+                },
+            ));
+        }
+
+        let base = file.preset.map(Preset::config).unwrap_or_default();
+        let config = Bme680Config {
+            temperature_oversampling: file
+                .temperature_oversampling
+                .unwrap_or(base.temperature_oversampling),
+            pressure_oversampling: file
+                .pressure_oversampling
+                .unwrap_or(base.pressure_oversampling),
+            humidity_oversampling: file
+                .humidity_oversampling
+                .unwrap_or(base.humidity_oversampling),
+            filter: file.filter.unwrap_or(base.filter),
+            gas_enabled: file.gas_enabled.unwrap_or(base.gas_enabled),
+            heater_enabled: file.heater_enabled.unwrap_or(base.heater_enabled),
+            heater_temperature: file.heater_temperature.unwrap_or(base.heater_temperature),
+            heater_duration: file.heater_duration.unwrap_or(base.heater_duration),
+        };
+        keyed(config.check())?;
+
+        if !file.heater_profile.is_empty() {
+            keyed(check_range(
+                "heater_profile",
+                file.heater_profile.len() as u32,
+                1,
+                HEATER_PROFILE_MAX_STEPS as u32,
+            ))?;
+        }
+        for (i, step) in file.heater_profile.iter().enumerate() {
+            keyed_as(
+                check_heater_temperature(step.temperature),
+                format!("heater_profile[{}].temperature", i),
+            )?;
+            keyed_as(
+                check_heater_duration(step.duration),
+                format!("heater_profile[{}].duration", i),
+            )?;
+        }
+
+        let sampling_interval = match file.sampling_interval_ms {
+            Some(interval) => {
+                // measurements can't be taken more often than they take
+                let duration = config.measurement_duration().as_millis() as u32;
+                keyed(check_range(
+                    "sampling_interval_ms",
+                    interval,
+                    duration,
+                    u32::MAX,
+                ))?;
+                Some(Duration::from_millis(interval as u64))
+            }
+            None => None,
+        };
+
+        Ok(SensorSetup {
+            device: file.device,
+            bus: file.bus,
+            address: file.address.unwrap_or_default(),
+            config,
+            heater_profile: file.heater_profile,
+            sampling_interval,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::fake_device;
+
+    fn error(json: &str) -> String {
+        serde_json::from_str::<SensorSetup>(json)
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn setups_are_read() {
+        let setup: SensorSetup = serde_json::from_str(
+            r#"{
+                "device": "/dev/i2c-1",
+                "preset": "weather",
+                "pressure_oversampling": "4x",
+                "filter": "size_15",
+                "heater_profile": [
+                    { "temperature": 200, "duration": 100 },
+                    { "temperature": 350, "duration": 50 }
+                ],
+                "sampling_interval_ms": 60000
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(setup.bus, BusType::I2c);
+        assert_eq!(setup.address, Bme680Address::Primary);
+        assert_eq!(
+            setup.config,
+            Bme680Config {
+                pressure_oversampling: Oversampling::_4X,
+                filter: FilterSize::Size15,
+                ..Preset::Weather.config()
+            }
+        );
+        assert_eq!(setup.sampling_interval, Some(Duration::from_secs(60)));
+
+        let mut sensor = fake_device(0);
+        setup.apply_to(&mut sensor).unwrap();
+        assert_eq!(sensor.config(), setup.config);
+        assert_eq!(sensor.get_heater_profile(), &setup.heater_profile[..]);
+
+        let setup: SensorSetup =
+            serde_json::from_str(r#"{ "device": "/dev/spidev0.0", "bus": "spi" }"#).unwrap();
+        assert_eq!(setup.config, Bme680Config::default());
+        assert_eq!(setup.sampling_interval, None);
+    }
+
+    #[test]
+    fn toml_setups_are_read() {
+        let setup: SensorSetup = toml::from_str(
+            r#"
+            device = "/dev/i2c-1"
+            address = "secondary"
+            preset = "indoor_air_quality"
+            heater_temperature = 300
+            sampling_interval_ms = 3000
+
+            [[heater_profile]]
+            temperature = 200
+            duration = 100
+
+            [[heater_profile]]
+            temperature = 350
+            duration = 50
+            "#,
+        )
+        .unwrap();
+        assert_eq!(setup.address, Bme680Address::Secondary);
+        assert_eq!(
+            setup.config,
+            Bme680Config {
+                heater_temperature: 300,
+                ..Preset::IndoorAirQuality.config()
+            }
+        );
+        assert_eq!(setup.heater_profile.len(), 2);
+        assert_eq!(setup.sampling_interval, Some(Duration::from_secs(3)));
+
+        let error = toml::from_str::<SensorSetup>(
+            r#"
+            device = "/dev/i2c-1"
+
+            [[heater_profile]]
+            temperature = 500
+            duration = 100
+            "#,
+        )
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("heater_profile[0].temperature: heater_temperature is 500"));
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        assert!(error(
+            r#"{ "device": "/dev/i2c-1", "heater_profile": [
+                { "temperature": 200, "duration": 100 },
+                { "temperature": 300, "duration": 5000 }
+            ] }"#
+        )
+        .starts_with("heater_profile[1].duration: heater_duration is 5000"));
+        assert!(
+            error(r#"{ "device": "/dev/i2c-1", "heater_temperature": 150 }"#)
+                .starts_with("heater_temperature: heater_temperature is 150")
+        );
+        assert!(
+            error(r#"{ "device": "/dev/i2c-1", "humidity_oversampling": "2x" }"#)
+                .starts_with("humidity_oversampling: ")
+        );
+        assert!(
+            error(r#"{ "device": "/dev/spidev0.0", "bus": "spi", "address": "primary" }"#)
+                .starts_with("address: ")
+        );
+        // an IAQ measurement takes 193 ms
+        assert!(error(
+            r#"{ "device": "/dev/i2c-1", "preset": "indoor_air_quality",
+                 "sampling_interval_ms": 100 }"#
+        )
+        .starts_with("sampling_interval_ms: sampling_interval_ms is 100"));
+
+        // values that don't parse and unknown keys
+        assert!(error(r#"{ "device": "/dev/i2c-1", "filter": "size_2" }"#)
+            .starts_with("filter: unknown variant `size_2`"));
+        assert!(error(r#"{ "device": "/dev/i2c-1", "heater": true }"#)
+            .starts_with("heater: unknown field `heater`"));
+        assert!(error(
+            r#"{ "device": "/dev/i2c-1", "heater_profile": [{ "temperature": "hot", "duration": 100 }] }"#
+        )
+        .starts_with("heater_profile[0].temperature: invalid type"));
+    }
+}
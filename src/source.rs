@@ -3,8 +3,12 @@
 #![allow(non_snake_case)]
 #![allow(unused)]
 
+#[cfg(feature = "ffi")]
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
+#[cfg(not(feature = "ffi"))]
+use crate::compensation::CalibrationData;
+
 /** BME680 General config */
 pub const BME680_POLL_PERIOD_MS: u8 = 10;
 
@@ -20,6 +24,42 @@ pub const BME680_COEFF_SIZE: u8 = 41;
 pub const BME680_COEFF_ADDR1_LEN: u8 = 25;
 pub const BME680_COEFF_ADDR2_LEN: u8 = 16;
 
+/** Array Index to Field data mapping for Calibration Data*/
+pub const BME680_T2_LSB_REG: usize = 1;
+pub const BME680_T2_MSB_REG: usize = 2;
+pub const BME680_T3_REG: usize = 3;
+pub const BME680_P1_LSB_REG: usize = 5;
+pub const BME680_P1_MSB_REG: usize = 6;
+pub const BME680_P2_LSB_REG: usize = 7;
+pub const BME680_P2_MSB_REG: usize = 8;
+pub const BME680_P3_REG: usize = 9;
+pub const BME680_P4_LSB_REG: usize = 11;
+pub const BME680_P4_MSB_REG: usize = 12;
+pub const BME680_P5_LSB_REG: usize = 13;
+pub const BME680_P5_MSB_REG: usize = 14;
+pub const BME680_P7_REG: usize = 15;
+pub const BME680_P6_REG: usize = 16;
+pub const BME680_P8_LSB_REG: usize = 19;
+pub const BME680_P8_MSB_REG: usize = 20;
+pub const BME680_P9_LSB_REG: usize = 21;
+pub const BME680_P9_MSB_REG: usize = 22;
+pub const BME680_P10_REG: usize = 23;
+pub const BME680_H2_MSB_REG: usize = 25;
+pub const BME680_H2_LSB_REG: usize = 26;
+pub const BME680_H1_LSB_REG: usize = 26;
+pub const BME680_H1_MSB_REG: usize = 27;
+pub const BME680_H3_REG: usize = 28;
+pub const BME680_H4_REG: usize = 29;
+pub const BME680_H5_REG: usize = 30;
+pub const BME680_H6_REG: usize = 31;
+pub const BME680_H7_REG: usize = 32;
+pub const BME680_T1_LSB_REG: usize = 33;
+pub const BME680_T1_MSB_REG: usize = 34;
+pub const BME680_GH2_LSB_REG: usize = 35;
+pub const BME680_GH2_MSB_REG: usize = 36;
+pub const BME680_GH1_REG: usize = 37;
+pub const BME680_GH3_REG: usize = 38;
+
 /** BME680 field_x related defines */
 pub const BME680_FIELD_LENGTH: u8 = 15;
 pub const BME680_FIELD_ADDR_OFFSET: u8 = 17;
@@ -170,3 +210,62 @@ pub const BME680_FILTER_POS: u8 = 2;
 pub const BME680_OST_POS: u8 = 5;
 pub const BME680_OSP_POS: u8 = 2;
 pub const BME680_RUN_GAS_POS: u8 = 4;
+
+/** Native counterparts of the driver's types, used when the C driver isn't built */
+#[cfg(not(feature = "ffi"))]
+pub type bme680_intf = u32;
+#[cfg(not(feature = "ffi"))]
+pub const bme680_intf_BME680_SPI_INTF: bme680_intf = 0;
+#[cfg(not(feature = "ffi"))]
+pub const bme680_intf_BME680_I2C_INTF: bme680_intf = 1;
+
+/** Sensor field data structure */
+#[cfg(not(feature = "ffi"))]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bme680_field_data {
+    pub status: u8,
+    pub gas_index: u8,
+    pub meas_index: u8,
+    pub temperature: i16,
+    pub pressure: u32,
+    pub humidity: u32,
+    pub gas_resistance: u32,
+}
+
+/** BME680 sensor settings structure which comprises of ODR, over-sampling and filter settings */
+#[cfg(not(feature = "ffi"))]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bme680_tph_sett {
+    pub os_hum: u8,
+    pub os_temp: u8,
+    pub os_pres: u8,
+    pub filter: u8,
+}
+
+/** BME680 gas sensor which comprises of gas settings and status parameters */
+#[cfg(not(feature = "ffi"))]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct bme680_gas_sett {
+    pub nb_conv: u8,
+    pub heatr_ctrl: u8,
+    pub run_gas: u8,
+    pub heatr_temp: u16,
+    pub heatr_dur: u16,
+}
+
+/** BME680 device structure, without the bus callbacks of the C driver */
+#[cfg(not(feature = "ffi"))]
+#[derive(Debug, Copy, Clone)]
+pub struct bme680_dev {
+    pub chip_id: u8,
+    pub dev_id: u8,
+    pub intf: bme680_intf,
+    pub mem_page: u8,
+    pub amb_temp: i8,
+    pub calib: CalibrationData,
+    pub tph_sett: bme680_tph_sett,
+    pub gas_sett: bme680_gas_sett,
+    pub power_mode: u8,
+    pub new_fields: u8,
+    pub info_msg: u8,
+}
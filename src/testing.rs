@@ -0,0 +1,744 @@
+//!
+//! Register-level simulation of a BME680 for tests without hardware.
+//!
+//! `Bme680Simulator` is an I2C bus with a single sensor on it, or a SPI bus with
+//! `Bme680Simulator::new_spi`: it models the chip id, the calibration registers, soft reset,
+//! the mode register and the field data registers. On SPI, it also models the two memory
+//! pages the registers are split into.
+//! Forced measurements finish immediately and report the environment given with
+//! `set_environment`, encoded with the simulator's calibration data. Like the real sensor,
+//! it reports no temperature, pressure or humidity if their oversampling is off.
+//!
+//! ```
+//! use bme680::testing::{Bme680Simulator, Environment, NoDelay};
+//! use bme680::{Bme680Address, Oversampling, BME680};
+//!
+//! let mut simulator = Bme680Simulator::new();
+//! simulator.set_environment(Environment {
+//!     temperature: 21.5,
+//!     ..Environment::default()
+//! });
+//! let mut sensor = BME680::new_i2c(simulator, Bme680Address::Primary, NoDelay).unwrap();
+//! sensor.set_temperature_oversampling(Oversampling::_2X).unwrap();
+//! let data = sensor.read_all().unwrap();
+//! assert!((data.temperature - 21.5).abs() < 0.05);
+//! ```
+//!
+
+use crate::compensation::{
+    calc_gas_resistance_f32, calc_humidity_f32, calc_pressure_f32, calc_temperature_f32,
+    CalibrationData,
+};
+use crate::source::*;
+use crate::Bme680Address;
+
+use core::fmt;
+
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use embedded_hal::blocking::spi;
+
+/// ADC value the sensor reports for skipped (not over-sampled) measurements
+const SKIPPED_ADC: u32 = 0x80000;
+
+///
+/// Conditions the simulated sensor measures
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Environment {
+    /// In °C
+    pub temperature: f32,
+    /// In Pa
+    pub pressure: f32,
+    /// Relative humidity in %
+    pub humidity: f32,
+    /// Gas resistance in Ohm
+    pub gas_resistance: f32,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Environment {
+            temperature: 22.0,
+            pressure: 101_325.0,
+            humidity: 45.0,
+            gas_resistance: 50_000.0,
+        }
+    }
+}
+
+///
+/// Raw readings of the sensor's ADCs, before compensation
+///
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct AdcReadings {
+    pub temperature: u32,
+    pub pressure: u32,
+    pub humidity: u16,
+    pub gas: u16,
+    pub gas_range: u8,
+}
+
+///
+/// Errors of the simulated bus
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SimulatorError {
+    /// Nothing answered at the address, the bus was set to fail or the sensor is attached
+    /// to the other kind of bus
+    Nack,
+}
+
+impl fmt::Display for SimulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimulatorError::Nack => write!(f, "no acknowledge"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SimulatorError {}
+
+///
+/// Calibration data of a typical sensor
+///
+pub fn typical_calibration() -> CalibrationData {
+    CalibrationData {
+        par_h1: 771,
+        par_h2: 1014,
+        par_h3: 0,
+        par_h4: 45,
+        par_h5: 20,
+        par_h6: 120,
+        par_h7: -100,
+        par_gh1: -30,
+        par_gh2: -5969,
+        par_gh3: 18,
+        par_t1: 26130,
+        par_t2: 26279,
+        par_t3: 3,
+        par_p1: 36166,
+        par_p2: -10395,
+        par_p3: 88,
+        par_p4: 6879,
+        par_p5: -142,
+        par_p6: 30,
+        par_p7: 41,
+        par_p8: -2965,
+        par_p9: -3176,
+        par_p10: 30,
+        res_heat_range: 1,
+        res_heat_val: 46,
+        range_sw_err: 0,
+    }
+}
+
+///
+/// Register contents `CalibrationData::from_registers` parses into `calib`.
+///
+pub fn calibration_registers(calib: &CalibrationData) -> [u8; BME680_COEFF_SIZE as usize] {
+    let mut coeff = [0_u8; BME680_COEFF_SIZE as usize];
+    let mut set_u16 = |lsb: usize, msb: usize, value: u16| {
+        coeff[lsb] = value as u8;
+        coeff[msb] = (value >> 8) as u8;
+    };
+    set_u16(BME680_T1_LSB_REG, BME680_T1_MSB_REG, calib.par_t1);
+    set_u16(BME680_T2_LSB_REG, BME680_T2_MSB_REG, calib.par_t2 as u16);
+    set_u16(BME680_P1_LSB_REG, BME680_P1_MSB_REG, calib.par_p1);
+    set_u16(BME680_P2_LSB_REG, BME680_P2_MSB_REG, calib.par_p2 as u16);
+    set_u16(BME680_P4_LSB_REG, BME680_P4_MSB_REG, calib.par_p4 as u16);
+    set_u16(BME680_P5_LSB_REG, BME680_P5_MSB_REG, calib.par_p5 as u16);
+    set_u16(BME680_P8_LSB_REG, BME680_P8_MSB_REG, calib.par_p8 as u16);
+    set_u16(BME680_P9_LSB_REG, BME680_P9_MSB_REG, calib.par_p9 as u16);
+    set_u16(BME680_GH2_LSB_REG, BME680_GH2_MSB_REG, calib.par_gh2 as u16);
+    coeff[BME680_T3_REG] = calib.par_t3 as u8;
+    coeff[BME680_P3_REG] = calib.par_p3 as u8;
+    coeff[BME680_P6_REG] = calib.par_p6 as u8;
+    coeff[BME680_P7_REG] = calib.par_p7 as u8;
+    coeff[BME680_P10_REG] = calib.par_p10;
+    /* par_h1 and par_h2 share their least significant register */
+    coeff[BME680_H1_MSB_REG] = (calib.par_h1 >> BME680_HUM_REG_SHIFT_VAL) as u8;
+    coeff[BME680_H2_MSB_REG] = (calib.par_h2 >> BME680_HUM_REG_SHIFT_VAL) as u8;
+    coeff[BME680_H1_LSB_REG] = (calib.par_h1 as u8 & BME680_BIT_H1_DATA_MSK)
+        | ((calib.par_h2 as u8) << BME680_HUM_REG_SHIFT_VAL);
+    coeff[BME680_H3_REG] = calib.par_h3 as u8;
+    coeff[BME680_H4_REG] = calib.par_h4 as u8;
+    coeff[BME680_H5_REG] = calib.par_h5 as u8;
+    coeff[BME680_H6_REG] = calib.par_h6;
+    coeff[BME680_H7_REG] = calib.par_h7 as u8;
+    coeff[BME680_GH1_REG] = calib.par_gh1 as u8;
+    coeff[BME680_GH3_REG] = calib.par_gh3 as u8;
+    coeff
+}
+
+///
+/// ADC value in `0..=max` for which the monotonic `f` comes closest to `target`
+///
+fn invert(max: u32, target: f32, f: impl Fn(u32) -> f32) -> u32 {
+    let increasing = f(max) >= f(0);
+    let (mut low, mut high) = (0, max);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if (f(mid) < target) == increasing {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let distance = |adc| {
+        let d = f(adc) - target;
+        if d < 0.0 {
+            -d
+        } else {
+            d
+        }
+    };
+    if low > 0 && distance(low - 1) < distance(low) {
+        low - 1
+    } else {
+        low
+    }
+}
+
+///
+/// A simulated BME680 on an I2C or SPI bus
+///
+#[derive(Debug, Clone)]
+pub struct Bme680Simulator {
+    address: u8,
+    spi: bool,
+    registers: [u8; 256],
+    calib: CalibrationData,
+    environment: Environment,
+    adc: Option<AdcReadings>,
+    measurements: u32,
+    data_ready: bool,
+    heater_stable: bool,
+    gas_valid: bool,
+    bus_error: bool,
+    locked: [bool; 256],
+}
+
+impl Default for Bme680Simulator {
+    fn default() -> Self {
+        Bme680Simulator::new()
+    }
+}
+
+impl Bme680Simulator {
+    ///
+    /// A sensor at the primary address with `typical_calibration()`.
+    ///
+    pub fn new() -> Self {
+        let mut simulator = Bme680Simulator {
+            address: Bme680Address::Primary as u8,
+            spi: false,
+            registers: [0; 256],
+            calib: typical_calibration(),
+            environment: Environment::default(),
+            adc: None,
+            measurements: 0,
+            data_ready: true,
+            heater_stable: true,
+            gas_valid: true,
+            bus_error: false,
+            locked: [false; 256],
+        };
+        simulator.program_calibration();
+        simulator.registers[BME680_CHIP_ID_ADDR as usize] = BME680_CHIP_ID;
+        simulator
+    }
+
+    ///
+    /// A sensor on a SPI bus with `typical_calibration()`.
+    ///
+    /// Addresses sent over SPI have 7 bits, the most significant bit tells reads from writes.
+    /// Apart from the status register, they address the memory page selected by its
+    /// `spi_mem_page` bit. Reads that run past the end of a page continue at its start.
+    ///
+    pub fn new_spi() -> Self {
+        Bme680Simulator {
+            spi: true,
+            ..Bme680Simulator::new()
+        }
+    }
+
+    pub fn with_address(mut self, address: Bme680Address) -> Self {
+        self.address = address as u8;
+        self
+    }
+
+    pub fn with_calibration(mut self, calib: CalibrationData) -> Self {
+        self.calib = calib;
+        self.program_calibration();
+        self
+    }
+
+    fn program_calibration(&mut self) {
+        let coeff = calibration_registers(&self.calib);
+        let (coeff1, coeff2) = coeff.split_at(BME680_COEFF_ADDR1_LEN as usize);
+        let addr1 = BME680_COEFF_ADDR1 as usize;
+        self.registers[addr1..addr1 + coeff1.len()].copy_from_slice(coeff1);
+        let addr2 = BME680_COEFF_ADDR2 as usize;
+        self.registers[addr2..addr2 + coeff2.len()].copy_from_slice(coeff2);
+        self.registers[BME680_ADDR_RES_HEAT_RANGE_ADDR as usize] =
+            (self.calib.res_heat_range << 4) & BME680_RHRANGE_MSK;
+        self.registers[BME680_ADDR_RES_HEAT_VAL_ADDR as usize] = self.calib.res_heat_val as u8;
+        self.registers[BME680_ADDR_RANGE_SW_ERR_ADDR as usize] =
+            ((self.calib.range_sw_err as u8) << 4) & BME680_RSERROR_MSK;
+    }
+
+    pub fn calibration(&self) -> CalibrationData {
+        self.calib
+    }
+
+    pub fn environment(&self) -> Environment {
+        self.environment
+    }
+
+    ///
+    /// Conditions reported by the following measurements.
+    ///
+    pub fn set_environment(&mut self, environment: Environment) {
+        self.environment = environment;
+    }
+
+    ///
+    /// Reports `readings` instead of the environment, e.g. for checking the compensated
+    /// values exactly. Skipped measurements are still reported as skipped.
+    ///
+    pub fn set_adc_readings(&mut self, readings: AdcReadings) {
+        self.adc = Some(readings);
+    }
+
+    pub fn register(&self, reg_addr: u8) -> u8 {
+        self.registers[reg_addr as usize]
+    }
+
+    pub fn set_register(&mut self, reg_addr: u8, value: u8) {
+        self.registers[reg_addr as usize] = value;
+    }
+
+    ///
+    /// Number of forced measurements the sensor ran.
+    ///
+    pub fn measurements(&self) -> u32 {
+        self.measurements
+    }
+
+    ///
+    /// If not ready, measurements never report new data.
+    ///
+    pub fn set_data_ready(&mut self, ready: bool) {
+        self.data_ready = ready;
+    }
+
+    ///
+    /// Whether the heater reaches its target temperature during gas measurements.
+    ///
+    pub fn set_heater_stable(&mut self, stable: bool) {
+        self.heater_stable = stable;
+    }
+
+    ///
+    /// Whether gas measurements are flagged as valid.
+    ///
+    pub fn set_gas_valid(&mut self, valid: bool) {
+        self.gas_valid = valid;
+    }
+
+    ///
+    /// Makes every bus transfer fail.
+    ///
+    pub fn set_bus_error(&mut self, error: bool) {
+        self.bus_error = error;
+    }
+
+    ///
+    /// Ignores writes to `reg_addr`, like a sensor that doesn't take the setting.
+    ///
+    pub fn lock_register(&mut self, reg_addr: u8) {
+        self.locked[reg_addr as usize] = true;
+    }
+
+    fn check_address(&self, address: u8) -> Result<(), SimulatorError> {
+        if self.bus_error || self.spi || address != self.address {
+            Err(SimulatorError::Nack)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_spi(&self) -> Result<(), SimulatorError> {
+        if self.bus_error || !self.spi {
+            Err(SimulatorError::Nack)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Register a 7 bit SPI address refers to in the selected memory page
+    fn spi_register(&self, spi_addr: u8) -> u8 {
+        let spi_addr = spi_addr & BME680_SPI_WR_MSK;
+        if spi_addr == BME680_MEM_PAGE_ADDR & BME680_SPI_WR_MSK {
+            // the status register is part of both pages
+            spi_addr
+        } else if self.registers[(BME680_MEM_PAGE_ADDR & BME680_SPI_WR_MSK) as usize]
+            & BME680_MEM_PAGE_MSK
+            == BME680_MEM_PAGE0
+        {
+            spi_addr
+        } else {
+            spi_addr | BME680_SPI_RD_MSK
+        }
+    }
+
+    /// Handles a SPI transfer, starting with the address byte
+    fn spi_transfer(&mut self, words: &mut [u8]) {
+        match words.split_first_mut() {
+            Some((&mut spi_addr, data)) if spi_addr & BME680_SPI_RD_MSK != 0 => {
+                self.spi_read(spi_addr, data)
+            }
+            _ => self.spi_write(words),
+        }
+    }
+
+    fn spi_read(&self, spi_addr: u8, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            let spi_addr = spi_addr.wrapping_add(i as u8);
+            *byte = self.registers[self.spi_register(spi_addr) as usize];
+        }
+    }
+
+    /// Writes address/value pairs with 7 bit addresses
+    fn spi_write(&mut self, pairs: &[u8]) {
+        for pair in pairs.chunks(2) {
+            if let [spi_addr, value] = *pair {
+                let reg_addr = self.spi_register(spi_addr);
+                self.write_register(reg_addr, value);
+            }
+        }
+    }
+
+    fn read_registers(&self, reg_addr: u8, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.registers[(reg_addr as usize + i) % 256];
+        }
+    }
+
+    /// Writes address/value pairs
+    fn write_registers(&mut self, pairs: &[u8]) {
+        for pair in pairs.chunks(2) {
+            if let [reg_addr, value] = *pair {
+                self.write_register(reg_addr, value);
+            }
+        }
+    }
+
+    fn write_register(&mut self, reg_addr: u8, value: u8) {
+        if self.locked[reg_addr as usize] {
+            return;
+        }
+        match reg_addr {
+            BME680_SOFT_RESET_ADDR if value == BME680_SOFT_RESET_CMD => self.soft_reset(),
+            BME680_CONF_T_P_MODE_ADDR => {
+                self.registers[reg_addr as usize] = value;
+                if value & BME680_MODE_MSK == BME680_FORCED_MODE {
+                    self.measure();
+                    // measurements finish immediately
+                    self.registers[reg_addr as usize] &= !BME680_MODE_MSK;
+                }
+            }
+            _ => self.registers[reg_addr as usize] = value,
+        }
+    }
+
+    fn soft_reset(&mut self) {
+        let first = BME680_FIELD0_ADDR as usize;
+        let last = BME680_CONF_ODR_FILT_ADDR as usize;
+        for register in &mut self.registers[first..=last] {
+            *register = 0;
+        }
+    }
+
+    fn measure(&mut self) {
+        self.measurements += 1;
+        if !self.data_ready {
+            return;
+        }
+        let calib = self.calib;
+        let env = self.environment;
+        let ctrl_meas = self.registers[BME680_CONF_T_P_MODE_ADDR as usize];
+        let ctrl_hum = self.registers[BME680_CONF_OS_H_ADDR as usize];
+        let ctrl_gas_0 = self.registers[BME680_CONF_HEAT_CTRL_ADDR as usize];
+        let ctrl_gas_1 = self.registers[BME680_CONF_ODR_RUN_GAS_NBC_ADDR as usize];
+
+        let (temp_adc, pres_adc, hum_adc) = match self.adc {
+            Some(adc) => (adc.temperature, adc.pressure, adc.humidity as u32),
+            None => {
+                let temp_adc = invert(0xfffff, env.temperature, |adc| {
+                    calc_temperature_f32(adc, &calib).0
+                });
+                let t_fine = calc_temperature_f32(temp_adc, &calib).1;
+                let pres_adc = invert(0xfffff, env.pressure, |adc| {
+                    calc_pressure_f32(adc, t_fine, &calib)
+                });
+                let hum_adc = invert(0xffff, env.humidity, |adc| {
+                    calc_humidity_f32(adc as u16, t_fine, &calib)
+                });
+                (temp_adc, pres_adc, hum_adc)
+            }
+        };
+        let temp_adc = if ctrl_meas & BME680_OST_MSK == 0 {
+            SKIPPED_ADC
+        } else {
+            temp_adc
+        };
+        let pres_adc = if ctrl_meas & BME680_OSP_MSK == 0 {
+            SKIPPED_ADC
+        } else {
+            pres_adc
+        };
+        let hum_adc = if ctrl_hum & BME680_OSH_MSK == 0 {
+            (SKIPPED_ADC >> 4) as u16
+        } else {
+            hum_adc as u16
+        };
+
+        let gas_index = ctrl_gas_1 & BME680_NBCONV_MSK;
+        let run_gas = ctrl_gas_1 & BME680_RUN_GAS_MSK != 0;
+        let (gas_adc, gas_range, gas_flags) = if run_gas {
+            let (gas_adc, gas_range) = match self.adc {
+                Some(adc) => (adc.gas, adc.gas_range),
+                None => {
+                    // pick the range that resolves the resistance best
+                    let (gas_adc, gas_range, _) = (0..16)
+                        .map(|range| {
+                            let adc = invert(0x3ff, env.gas_resistance, |adc| {
+                                calc_gas_resistance_f32(adc as u16, range, &calib)
+                            });
+                            let error = calc_gas_resistance_f32(adc as u16, range, &calib)
+                                - env.gas_resistance;
+                            (adc as u16, range, error * error)
+                        })
+                        .fold((0, 0, f32::MAX), |best, candidate| {
+                            if candidate.2 < best.2 {
+                                candidate
+                            } else {
+                                best
+                            }
+                        });
+                    (gas_adc, gas_range)
+                }
+            };
+            let heater_on = ctrl_gas_0 & BME680_HCTRL_MSK == BME680_ENABLE_HEATER;
+            let gas_wait = self.registers[(BME680_GAS_WAIT0_ADDR + gas_index) as usize];
+            let mut flags = 0;
+            if self.gas_valid {
+                flags |= BME680_GASM_VALID_MSK;
+            }
+            if self.heater_stable && heater_on && gas_wait != 0 {
+                flags |= BME680_HEAT_STAB_MSK;
+            }
+            (gas_adc, gas_range, flags)
+        } else {
+            (0, 0, 0)
+        };
+
+        let field = &mut self.registers[BME680_FIELD0_ADDR as usize..];
+        field[0] = BME680_NEW_DATA_MSK | gas_index;
+        field[1] = self.measurements as u8;
+        field[2] = (pres_adc >> 12) as u8;
+        field[3] = (pres_adc >> 4) as u8;
+        field[4] = (pres_adc << 4) as u8;
+        field[5] = (temp_adc >> 12) as u8;
+        field[6] = (temp_adc >> 4) as u8;
+        field[7] = (temp_adc << 4) as u8;
+        field[8] = (hum_adc >> 8) as u8;
+        field[9] = hum_adc as u8;
+        field[13] = (gas_adc >> 2) as u8;
+        field[14] = ((gas_adc << 6) as u8) | gas_flags | gas_range;
+    }
+}
+
+impl WriteRead for Bme680Simulator {
+    type Error = SimulatorError;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), SimulatorError> {
+        self.check_address(address)?;
+        self.read_registers(bytes[0], buffer);
+        Ok(())
+    }
+}
+
+impl Write for Bme680Simulator {
+    type Error = SimulatorError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), SimulatorError> {
+        self.check_address(address)?;
+        self.write_registers(bytes);
+        Ok(())
+    }
+}
+
+impl spi::Transfer<u8> for Bme680Simulator {
+    type Error = SimulatorError;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], SimulatorError> {
+        self.check_spi()?;
+        self.spi_transfer(words);
+        Ok(words)
+    }
+}
+
+impl spi::Write<u8> for Bme680Simulator {
+    type Error = SimulatorError;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), SimulatorError> {
+        self.check_spi()?;
+        self.spi_write(words);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+mod asynch {
+    use super::{Bme680Simulator, SimulatorError};
+    use crate::source::BME680_SPI_RD_MSK;
+    use embedded_hal_async::i2c::{
+        Error, ErrorKind, ErrorType, I2c, NoAcknowledgeSource, Operation, SevenBitAddress,
+    };
+    use embedded_hal_async::spi;
+
+    impl Error for SimulatorError {
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address)
+        }
+    }
+
+    impl ErrorType for Bme680Simulator {
+        type Error = SimulatorError;
+    }
+
+    impl spi::Error for SimulatorError {
+        fn kind(&self) -> spi::ErrorKind {
+            spi::ErrorKind::Other
+        }
+    }
+
+    impl spi::ErrorType for Bme680Simulator {
+        type Error = SimulatorError;
+    }
+
+    impl spi::SpiDevice for Bme680Simulator {
+        async fn transaction(
+            &mut self,
+            operations: &mut [spi::Operation<'_, u8>],
+        ) -> Result<(), SimulatorError> {
+            self.check_spi()?;
+            let mut spi_addr = 0;
+            for operation in operations {
+                match operation {
+                    // the address of a read, the data follows in the next operation
+                    spi::Operation::Write(&[addr]) if addr & BME680_SPI_RD_MSK != 0 => {
+                        spi_addr = addr
+                    }
+                    spi::Operation::Write(bytes) => self.spi_write(bytes),
+                    spi::Operation::Read(buffer) => self.spi_read(spi_addr, buffer),
+                    spi::Operation::TransferInPlace(words) => self.spi_transfer(words),
+                    // not used by the driver
+                    spi::Operation::Transfer(..) | spi::Operation::DelayNs(_) => {}
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl I2c for Bme680Simulator {
+        async fn transaction(
+            &mut self,
+            address: SevenBitAddress,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), SimulatorError> {
+            self.check_address(address)?;
+            let mut reg_addr = 0;
+            for operation in operations {
+                match operation {
+                    Operation::Write(bytes) => {
+                        reg_addr = bytes[0];
+                        self.write_registers(bytes);
+                    }
+                    Operation::Read(buffer) => self.read_registers(reg_addr, buffer),
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+///
+/// Delay provider that returns immediately
+///
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NoDelay;
+
+impl DelayMs<u32> for NoDelay {
+    fn delay_ms(&mut self, _ms: u32) {}
+}
+
+#[cfg(feature = "async")]
+impl embedded_hal_async::delay::DelayNs for NoDelay {
+    async fn delay_ns(&mut self, _ns: u32) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibration_round_trip() {
+        let calib = typical_calibration();
+        let simulator = Bme680Simulator::new().with_calibration(calib);
+        let mut coeff = [0_u8; BME680_COEFF_SIZE as usize];
+        let (coeff1, coeff2) = coeff.split_at_mut(BME680_COEFF_ADDR1_LEN as usize);
+        simulator.read_registers(BME680_COEFF_ADDR1, coeff1);
+        simulator.read_registers(BME680_COEFF_ADDR2, coeff2);
+        let parsed = CalibrationData::from_registers(
+            &coeff,
+            simulator.register(BME680_ADDR_RES_HEAT_RANGE_ADDR),
+            simulator.register(BME680_ADDR_RES_HEAT_VAL_ADDR),
+            simulator.register(BME680_ADDR_RANGE_SW_ERR_ADDR),
+        );
+        assert_eq!(parsed, calib);
+    }
+
+    #[test]
+    fn soft_reset_clears_configuration() {
+        let mut simulator = Bme680Simulator::new();
+        simulator.write_registers(&[BME680_CONF_OS_H_ADDR, 3]);
+        simulator.write_registers(&[BME680_SOFT_RESET_ADDR, BME680_SOFT_RESET_CMD]);
+        assert_eq!(simulator.register(BME680_CONF_OS_H_ADDR), 0);
+        assert_eq!(simulator.register(BME680_CHIP_ID_ADDR), BME680_CHIP_ID);
+    }
+
+    #[test]
+    fn forced_measurement() {
+        let mut simulator = Bme680Simulator::new();
+        simulator.write_registers(&[BME680_CONF_T_P_MODE_ADDR, 0x20 | BME680_FORCED_MODE]);
+        assert_eq!(simulator.measurements(), 1);
+        assert_eq!(
+            simulator.register(BME680_CONF_T_P_MODE_ADDR) & BME680_MODE_MSK,
+            BME680_SLEEP_MODE
+        );
+        assert_ne!(
+            simulator.register(BME680_FIELD0_ADDR) & BME680_NEW_DATA_MSK,
+            0
+        );
+    }
+}
@@ -0,0 +1,500 @@
+//!
+//! Recording the register accesses of a sensor and replaying them.
+//!
+//! A `Recorder` wraps the bus interface of a sensor and logs every read and write, with its
+//! data, the time since the recording started and whether it failed. The trace is plain
+//! text with one access per line, e.g. `1520 R 1d 80000b6e0a...` or `1530 W 74 01 ok`.
+//!
+//! A `Replay` answers the driver's accesses from a recorded trace, so the readings of the
+//! recorded session can be reproduced without the sensor. The driver has to access the
+//! registers in the same order as when recording, i.e. with the same settings and calls.
+//!
+
+use crate::errors::SensorError;
+use crate::interface::Interface;
+use crate::source::*;
+use crate::{Bme680Address, BME680};
+
+use embedded_hal::blocking::delay::DelayMs;
+use log::error;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+const TRACE_HEADER: &str = "# bme680 trace";
+
+///
+/// Bus a trace was recorded on
+///
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TraceBus {
+    I2c(Bme680Address),
+    Spi,
+}
+
+impl fmt::Display for TraceBus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceBus::I2c(address) => write!(f, "i2c 0x{:x}", *address as u8),
+            TraceBus::Spi => write!(f, "spi"),
+        }
+    }
+}
+
+impl FromStr for TraceBus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.trim() {
+            "spi" => Ok(TraceBus::Spi),
+            "i2c 0x76" => Ok(TraceBus::I2c(Bme680Address::Primary)),
+            "i2c 0x77" => Ok(TraceBus::I2c(Bme680Address::Secondary)),
+            other => Err(format!("unknown bus '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+///
+/// A single register access
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Access {
+    pub kind: AccessKind,
+    /// Time since the recording started
+    pub timestamp: Duration,
+    pub reg_addr: u8,
+    /// Data read from or written to the sensor, laid out as for `Interface`
+    pub data: Vec<u8>,
+    /// Debug output of the bus error, if the access failed
+    pub error: Option<String>,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            AccessKind::Read => "R",
+            AccessKind::Write => "W",
+        };
+        write!(
+            f,
+            "{} {} {:02x} ",
+            self.timestamp.as_micros(),
+            kind,
+            self.reg_addr
+        )?;
+        if self.data.is_empty() {
+            write!(f, "-")?;
+        }
+        for byte in &self.data {
+            write!(f, "{:02x}", byte)?;
+        }
+        match &self.error {
+            None => write!(f, " ok"),
+            Some(error) => write!(f, " err {}", error.replace('\n', " ")),
+        }
+    }
+}
+
+impl FromStr for Access {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut fields = s.splitn(5, ' ');
+        let mut next = |name| fields.next().ok_or(format!("missing {}", name));
+        let timestamp = next("timestamp")?
+            .parse()
+            .map(Duration::from_micros)
+            .map_err(|e| format!("invalid timestamp: {}", e))?;
+        let kind = match next("access kind")? {
+            "R" => AccessKind::Read,
+            "W" => AccessKind::Write,
+            other => return Err(format!("invalid access kind '{}'", other)),
+        };
+        let reg_addr = u8::from_str_radix(next("register address")?, 16)
+            .map_err(|e| format!("invalid register address: {}", e))?;
+        let data = match next("data")? {
+            "-" => Vec::new(),
+            // sliced by bytes below
+            hex if !hex.is_ascii() => return Err("invalid data: not hexadecimal".to_string()),
+            hex if hex.len() % 2 == 0 => (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("invalid data: {}", e))?,
+            _ => return Err("invalid data: odd number of digits".to_string()),
+        };
+        let error = match next("result")? {
+            "ok" => None,
+            result => match result.strip_prefix("err ") {
+                Some(error) => Some(error.to_string()),
+                None => return Err(format!("invalid result '{}'", result)),
+            },
+        };
+        Ok(Access {
+            kind,
+            timestamp,
+            reg_addr,
+            data,
+            error,
+        })
+    }
+}
+
+///
+/// Bus interface that writes every access of the wrapped `interface` to a trace
+///
+/// The trace is flushed after every access, so it is complete up to a crash. If writing it
+/// fails, the recording stops and the error is returned by `finish`; the sensor keeps
+/// working.
+///
+pub struct Recorder<I, W: Write> {
+    interface: I,
+    bus: TraceBus,
+    sink: W,
+    start: Instant,
+    io_error: Option<io::Error>,
+}
+
+impl<I, W: Write> Recorder<I, W> {
+    ///
+    /// Records the accesses of `interface`, a sensor on `bus`, to `sink`.
+    ///
+    pub fn new(interface: I, bus: TraceBus, mut sink: W) -> Self {
+        let io_error = writeln!(sink, "{} {}", TRACE_HEADER, bus).err();
+        Recorder {
+            interface,
+            bus,
+            sink,
+            start: Instant::now(),
+            io_error,
+        }
+    }
+
+    ///
+    /// Bus the recorded sensor is attached to.
+    ///
+    pub fn bus(&self) -> TraceBus {
+        self.bus
+    }
+
+    ///
+    /// Stops recording and gives back the interface and the sink.
+    ///
+    pub fn finish(mut self) -> io::Result<(I, W)> {
+        if let Some(e) = self.io_error.take() {
+            return Err(e);
+        }
+        self.sink.flush()?;
+        Ok((self.interface, self.sink))
+    }
+
+    fn record<E: fmt::Debug>(
+        &mut self,
+        kind: AccessKind,
+        reg_addr: u8,
+        data: &[u8],
+        result: &Result<(), E>,
+    ) {
+        if self.io_error.is_some() {
+            return;
+        }
+        let access = Access {
+            kind,
+            timestamp: self.start.elapsed(),
+            reg_addr,
+            data: data.to_vec(),
+            error: result.as_ref().err().map(|e| format!("{:?}", e)),
+        };
+        if let Err(e) = writeln!(self.sink, "{}", access).and_then(|_| self.sink.flush()) {
+            error!("recording stopped: {}", e);
+            self.io_error = Some(e);
+        }
+    }
+}
+
+impl<I> Recorder<I, BufWriter<File>> {
+    ///
+    /// Records the accesses of `interface` to the file `path`, replacing its contents.
+    ///
+    pub fn create<P: AsRef<Path>>(interface: I, bus: TraceBus, path: P) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Recorder::new(interface, bus, BufWriter::new(file)))
+    }
+}
+
+impl<I: Interface, W: Write> Interface for Recorder<I, W> {
+    type Error = I::Error;
+
+    fn read(&mut self, reg_addr: u8, data: &mut [u8]) -> Result<(), I::Error> {
+        let result = self.interface.read(reg_addr, data);
+        self.record(AccessKind::Read, reg_addr, data, &result);
+        result
+    }
+
+    fn write(&mut self, reg_addr: u8, data: &[u8]) -> Result<(), I::Error> {
+        let result = self.interface.write(reg_addr, data);
+        self.record(AccessKind::Write, reg_addr, data, &result);
+        result
+    }
+}
+
+///
+/// Errors of a replayed bus
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayError {
+    /// The access failed when it was recorded, with this error
+    Recorded(String),
+    /// The driver didn't access the registers as recorded in line `line` of the trace
+    Mismatch { line: usize, expected: Access },
+    /// All recorded accesses were replayed
+    EndOfTrace,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Recorded(error) => write!(f, "recorded error: {}", error),
+            ReplayError::Mismatch { line, expected } => {
+                write!(f, "access differs from line {}: {}", line, expected)
+            }
+            ReplayError::EndOfTrace => write!(f, "end of trace"),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+///
+/// Bus interface that answers accesses from a recorded trace
+///
+#[derive(Debug, Clone)]
+pub struct Replay {
+    bus: TraceBus,
+    accesses: VecDeque<(usize, Access)>,
+}
+
+impl Replay {
+    ///
+    /// Reads a trace as written by `Recorder`.
+    ///
+    pub fn from_reader<R: BufRead>(reader: R) -> io::Result<Self> {
+        let invalid = |line, message| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", line, message),
+            )
+        };
+        let mut lines = reader.lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        let bus = header
+            .strip_prefix(TRACE_HEADER)
+            .ok_or_else(|| invalid(1, "not a bme680 trace".to_string()))?
+            .parse()
+            .map_err(|e| invalid(1, e))?;
+        let mut accesses = VecDeque::new();
+        for (i, line) in lines.enumerate() {
+            let line_number = i + 2;
+            let access = line?.parse().map_err(|e| invalid(line_number, e))?;
+            accesses.push_back((line_number, access));
+        }
+        Ok(Replay { bus, accesses })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Replay::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn bus(&self) -> TraceBus {
+        self.bus
+    }
+
+    ///
+    /// Number of recorded accesses that weren't replayed yet.
+    ///
+    pub fn remaining(&self) -> usize {
+        self.accesses.len()
+    }
+
+    ///
+    /// Takes the next access, if it is one of `kind` to `reg_addr` that `matches`.
+    ///
+    fn next(
+        &mut self,
+        kind: AccessKind,
+        reg_addr: u8,
+        matches: impl FnOnce(&[u8]) -> bool,
+    ) -> Result<Access, ReplayError> {
+        let (line, access) = self.accesses.pop_front().ok_or(ReplayError::EndOfTrace)?;
+        if access.kind != kind || access.reg_addr != reg_addr || !matches(&access.data) {
+            error!("replay diverged from line {}: {}", line, access);
+            return Err(ReplayError::Mismatch {
+                line,
+                expected: access,
+            });
+        }
+        Ok(access)
+    }
+
+    fn result(access: Access) -> Result<(), ReplayError> {
+        match access.error {
+            Some(error) => Err(ReplayError::Recorded(error)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Interface for Replay {
+    type Error = ReplayError;
+
+    fn read(&mut self, reg_addr: u8, data: &mut [u8]) -> Result<(), ReplayError> {
+        let access = self.next(AccessKind::Read, reg_addr, |recorded| {
+            recorded.len() == data.len()
+        })?;
+        data.copy_from_slice(&access.data);
+        Replay::result(access)
+    }
+
+    fn write(&mut self, reg_addr: u8, data: &[u8]) -> Result<(), ReplayError> {
+        let access = self.next(AccessKind::Write, reg_addr, |recorded| recorded == data)?;
+        Replay::result(access)
+    }
+}
+
+fn bus_config(bus: TraceBus) -> (bme680_intf, u8) {
+    match bus {
+        TraceBus::I2c(address) => (bme680_intf_BME680_I2C_INTF, address as u8),
+        TraceBus::Spi => (bme680_intf_BME680_SPI_INTF, 0),
+    }
+}
+
+impl<I, W, D> BME680<Recorder<I, W>, D>
+where
+    I: Interface,
+    W: Write,
+    D: DelayMs<u32>,
+{
+    ///
+    /// Initializes the sensor behind `recorder`, recording from the first access on.
+    ///
+    pub fn new_recorded(recorder: Recorder<I, W>, delay: D) -> Result<Self, SensorError> {
+        let (intf, dev_id) = bus_config(recorder.bus());
+        BME680::init(recorder, delay, intf, dev_id)
+    }
+}
+
+impl<D> BME680<Replay, D>
+where
+    D: DelayMs<u32>,
+{
+    ///
+    /// Initializes a sensor from a recorded trace. As the sensor isn't there, `delay` can
+    /// return immediately.
+    ///
+    pub fn new_replayed(replay: Replay, delay: D) -> Result<Self, SensorError> {
+        let (intf, dev_id) = bus_config(replay.bus());
+        BME680::init(replay, delay, intf, dev_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::I2cInterface;
+    use crate::testing::{Bme680Simulator, Environment, NoDelay};
+    use crate::Oversampling;
+
+    fn record_session() -> Vec<u8> {
+        let mut simulator = Bme680Simulator::new();
+        simulator.set_environment(Environment {
+            temperature: 18.25,
+            ..Environment::default()
+        });
+        let bus = TraceBus::I2c(Bme680Address::Primary);
+        let interface = I2cInterface::new(simulator, Bme680Address::Primary);
+        let recorder = Recorder::new(interface, bus, Vec::new());
+        let mut sensor = BME680::new_recorded(recorder, NoDelay).unwrap();
+        sensor
+            .set_temperature_oversampling(Oversampling::_2X)
+            .unwrap();
+        sensor.set_enable_gas_resistence(true);
+        for _ in 0..3 {
+            sensor.read_all().unwrap();
+        }
+        let (recorder, _) = sensor.release();
+        recorder.finish().unwrap().1
+    }
+
+    #[test]
+    fn access_round_trip() {
+        let access = Access {
+            kind: AccessKind::Read,
+            timestamp: Duration::from_micros(1520),
+            reg_addr: 0x1d,
+            data: vec![0x80, 0x00, 0x0b],
+            error: Some("Nack".to_string()),
+        };
+        assert_eq!(access.to_string(), "1520 R 1d 80000b err Nack");
+        assert_eq!(access.to_string().parse(), Ok(access));
+
+        for line in ["1520 R 1d 8é ok", "1520 R 1d éé ok", "1520 R 1d 800 ok"] {
+            assert!(line.parse::<Access>().is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn replay_reproduces_readings() {
+        let trace = record_session();
+        let replay = Replay::from_reader(trace.as_slice()).unwrap();
+        assert_eq!(replay.bus(), TraceBus::I2c(Bme680Address::Primary));
+        let recorded = replay.remaining();
+        assert!(recorded > 0);
+
+        let mut sensor = BME680::new_replayed(replay, NoDelay).unwrap();
+        sensor
+            .set_temperature_oversampling(Oversampling::_2X)
+            .unwrap();
+        sensor.set_enable_gas_resistence(true);
+        for _ in 0..3 {
+            let data = sensor.read_all().unwrap();
+            assert!((data.temperature - 18.25).abs() < 0.05);
+            assert!(data.gas_resistance.is_some());
+        }
+        let (replay, _) = sensor.release();
+        assert_eq!(replay.remaining(), 0);
+
+        // different settings make the driver diverge from the trace
+        let mut sensor =
+            BME680::new_replayed(Replay::from_reader(trace.as_slice()).unwrap(), NoDelay).unwrap();
+        sensor
+            .set_temperature_oversampling(Oversampling::_4X)
+            .unwrap();
+        let error = sensor.read_all().unwrap_err();
+        let cause = std::error::Error::source(&error).unwrap();
+        assert!(matches!(
+            cause.downcast_ref::<ReplayError>(),
+            Some(ReplayError::Mismatch { .. })
+        ));
+        assert!(recorded > sensor.release().0.remaining());
+    }
+
+    #[test]
+    fn invalid_traces_are_rejected() {
+        let error = Replay::from_reader(&b"# bme680 trace spi\n12 X 1d - ok\n"[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("line 2:"));
+        assert!(Replay::from_reader(&b"12 R 1d - ok\n"[..]).is_err());
+        let error = Replay::from_reader("# bme680 trace spi\n12 R 1d 0é ok\n".as_bytes());
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}